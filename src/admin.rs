@@ -0,0 +1,338 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::commands::{exec_cmd, Command, CommandResult, Output};
+use crate::server::Server;
+
+// Increased when a field of Request, Response or CommandResult is removed or changes meaning
+const API_VERSION: u32 = 1;
+// The line is read before the token is checked, so anyone reaching the port could make it grow
+const MAX_REQUEST_SIZE: u64 = 16 * 1024;
+
+// One json per line, e.g. {"command": "list", "token": "secret"}
+#[derive(Debug, Deserialize)]
+struct Request {
+    command: String,
+    token: Option<String>,
+}
+
+// One json per line. result is only there for the commands that have one, e.g. list:
+// {
+//   "version": 1,
+//   "success": true,
+//   "output": "Connected players: ...",
+//   "result": {
+//     "type": "players",
+//     "players": [{
+//       "id": "2d1c1e0b-5d6c-4bfc-9a38-2cba1ff3b1a5",
+//       "name": "Mario",
+//       "captured": null,
+//       "moons": 12,
+//       "stage": "CapWorldHomeStage",
+//       "is_seeking": false
+//     }]
+//   }
+// }
+// ban gives {"type": "banned", "ids": [...]}, announce {"type": "announced", "delivered": 3} and
+// tag leaderboard {"type": "leaderboard", "players": [{"id", "name", "seconds", "is_seeking"}]}
+#[derive(Debug, Serialize)]
+#[cfg_attr(test, derive(Deserialize, PartialEq))]
+struct Response {
+    version: u32,
+    success: bool,
+    output: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<CommandResult>,
+}
+
+impl Response {
+    fn error(output: impl ToString) -> Self {
+        Self {
+            version: API_VERSION,
+            success: false,
+            output: output.to_string(),
+            result: None,
+        }
+    }
+}
+
+pub async fn listen(server: Arc<Server>) -> Result<()> {
+    let settings = server.settings.read().await;
+    let address = SocketAddr::new(settings.admin.address, u16::try_from(settings.admin.port)?);
+
+    // Anyone reaching the port could run any command
+    if !address.ip().is_loopback() && settings.admin.token.is_none() {
+        return Err(eyre!(
+            "admin.token is required when admin.address isn't a loopback address"
+        ));
+    }
+
+    drop(settings);
+
+    let listener = TcpListener::bind(address).await?;
+
+    info!(addr = %address, "Admin api listening");
+
+    serve(listener, server).await
+}
+
+async fn serve(listener: TcpListener, server: Arc<Server>) -> Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let server = server.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(server, socket).await {
+                debug!(error = %e, "Admin connection exited with error");
+            }
+        });
+    }
+}
+
+async fn handle_connection(server: Arc<Server>, socket: TcpStream) -> Result<()> {
+    let source = format!("admin {}", socket.peer_addr()?);
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+
+    loop {
+        line.clear();
+
+        let read = (&mut reader)
+            .take(MAX_REQUEST_SIZE + 1)
+            .read_until(b'\n', &mut line)
+            .await?;
+
+        if read == 0 {
+            return Ok(());
+        }
+
+        let too_long = line.last() != Some(&b'\n') && read as u64 > MAX_REQUEST_SIZE;
+
+        let response = if too_long {
+            Response::error(format!(
+                "Invalid request: longer than {} bytes",
+                MAX_REQUEST_SIZE
+            ))
+        } else {
+            match serde_json::from_slice::<Request>(&line) {
+                Ok(request) => handle_request(&server, request, &source).await,
+                Err(e) => Response::error(format!("Invalid request: {}", e)),
+            }
+        };
+
+        let mut serialized = serde_json::to_vec(&response)?;
+        serialized.push(b'\n');
+
+        writer.write_all(&serialized).await?;
+
+        // The rest of the line would be read as another request
+        if too_long {
+            return Err(eyre!("Request longer than {} bytes", MAX_REQUEST_SIZE));
+        }
+    }
+}
+
+async fn handle_request(server: &Arc<Server>, request: Request, source: &str) -> Response {
+    let token = server.settings.read().await.admin.token.clone();
+
+    if token.is_some() && request.token != token {
+        return Response::error("Invalid token");
+    }
+
+    let cmd = match Command::parse(request.command.clone()) {
+        Ok(cmd) => cmd,
+        Err(message) => return Response::error(message),
+    };
+
+    if !cmd.is_unknown() {
+        server.audit(source, request.command.trim()).await;
+    }
+
+    let mut output = Output::captured();
+
+    exec_cmd(server.clone(), cmd, &mut output).await;
+
+    Response {
+        version: API_VERSION,
+        success: !output.failed(),
+        output: output.text(),
+        result: output.take_result(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use uuid::Uuid;
+
+    use super::{serve, Response, API_VERSION, MAX_REQUEST_SIZE};
+    use crate::commands::{CommandResult, ListedPlayer, TagTime};
+    use crate::settings::Settings;
+    use crate::testing::{start, Client};
+
+    fn round_trip(response: &Response) -> Value {
+        let value = serde_json::to_value(response).unwrap();
+
+        assert_eq!(
+            &serde_json::from_value::<Response>(value.clone()).unwrap(),
+            response
+        );
+
+        value
+    }
+
+    #[test]
+    fn documented_example() {
+        let example = json!({
+            "version": 1,
+            "success": true,
+            "output": "Connected players: ...",
+            "result": {
+                "type": "players",
+                "players": [{
+                    "id": "2d1c1e0b-5d6c-4bfc-9a38-2cba1ff3b1a5",
+                    "name": "Mario",
+                    "captured": null,
+                    "moons": 12,
+                    "stage": "CapWorldHomeStage",
+                    "is_seeking": false
+                }]
+            }
+        });
+
+        let response = serde_json::from_value::<Response>(example.clone()).unwrap();
+
+        assert_eq!(
+            response.result,
+            Some(CommandResult::Players {
+                players: vec![ListedPlayer {
+                    id: Uuid::parse_str("2d1c1e0b-5d6c-4bfc-9a38-2cba1ff3b1a5").unwrap(),
+                    name: "Mario".to_owned(),
+                    captured: None,
+                    moons: 12,
+                    stage: Some("CapWorldHomeStage".to_owned()),
+                    is_seeking: false,
+                }]
+            })
+        );
+        assert_eq!(round_trip(&response), example);
+    }
+
+    #[test]
+    fn other_results() {
+        let id = Uuid::new_v4();
+
+        for (result, expected) in [
+            (
+                CommandResult::Banned { ids: vec![id] },
+                json!({"type": "banned", "ids": [id]}),
+            ),
+            (
+                CommandResult::Announced { delivered: 3 },
+                json!({"type": "announced", "delivered": 3}),
+            ),
+            (
+                CommandResult::Leaderboard {
+                    players: vec![TagTime {
+                        id,
+                        name: "Luigi".to_owned(),
+                        seconds: 90,
+                        is_seeking: true,
+                    }],
+                },
+                json!({
+                    "type": "leaderboard",
+                    "players": [{"id": id, "name": "Luigi", "seconds": 90, "is_seeking": true}]
+                }),
+            ),
+        ] {
+            let response = Response {
+                version: API_VERSION,
+                success: true,
+                output: String::new(),
+                result: Some(result),
+            };
+
+            assert_eq!(round_trip(&response)["result"], expected);
+        }
+    }
+
+    #[test]
+    fn error_has_no_result() {
+        let value = round_trip(&Response::error("Invalid token"));
+
+        assert_eq!(
+            value,
+            json!({"version": API_VERSION, "success": false, "output": "Invalid token"})
+        );
+    }
+
+    async fn request(
+        lines: &mut BufReader<TcpStream>,
+        request: impl AsRef<[u8]>,
+    ) -> Option<Response> {
+        // Fails once the server closed the connection, read_line tells it
+        let _ = lines.get_mut().write_all(request.as_ref()).await;
+
+        let mut line = String::new();
+        lines.read_line(&mut line).await.ok()?;
+
+        serde_json::from_str(&line).ok()
+    }
+
+    #[tokio::test]
+    async fn requests_over_a_socket() {
+        let mut settings = Settings::default();
+        settings.admin.token = Some("secret".to_owned());
+
+        let (server, addr) = start(settings).await;
+        let alice = Client::connect(&server, addr, "alice").await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, server.clone()));
+
+        let mut lines = BufReader::new(TcpStream::connect(admin).await.unwrap());
+
+        let response = request(
+            &mut lines,
+            concat!(r#"{"command": "list", "token": "wrong"}"#, "\n"),
+        )
+        .await;
+        assert_eq!(response, Some(Response::error("Invalid token")));
+
+        let response = request(
+            &mut lines,
+            concat!(r#"{"command": "list", "token": "secret"}"#, "\n"),
+        )
+        .await
+        .unwrap();
+
+        assert!(response.success);
+        assert!(matches!(
+            response.result,
+            Some(CommandResult::Players { players })
+                if players.len() == 1 && players[0].id == alice.id && players[0].name == "alice"
+        ));
+
+        // Refused without waiting for the end of the line, and the connection is closed
+        let response = request(&mut lines, vec![b'a'; MAX_REQUEST_SIZE as usize + 1])
+            .await
+            .unwrap();
+
+        assert!(!response.success);
+        assert!(request(&mut lines, r#"{"command": "list"}"#)
+            .await
+            .is_none());
+    }
+}
@@ -1,20 +1,27 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
 use std::process::exit;
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
+use chrono::Utc;
 use futures::future::join_all;
+use ipnet::IpNet;
 use owo_colors::OwoColorize;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::time::sleep;
 use tracing::{error, info};
 use uuid::Uuid;
 
-use crate::packet::{Content, Packet, TagUpdate};
+use crate::packet::{ConnectionType, Content, Packet, TagUpdate, COSTUME_SIZE};
 use crate::server::Server;
-use crate::settings::{FlipPov, Settings};
+use crate::settings::{parse_ip_range, FlipPov, Settings};
 
 trait IsWildcard {
     fn is_wildcard(&self) -> bool;
@@ -101,6 +108,68 @@ Here is the list of the valid stages
         Ok(stage)
     }
 
+    const ALL: [Self; 17] = [
+        Self::Cap,
+        Self::Cascade,
+        Self::Sand,
+        Self::Lake,
+        Self::Wooded,
+        Self::Cloud,
+        Self::Lost,
+        Self::Metro,
+        Self::Sea,
+        Self::Snow,
+        Self::Lunch,
+        Self::Ruined,
+        Self::Bowser,
+        Self::Moon,
+        Self::Mush,
+        Self::Dark,
+        Self::Darker,
+    ];
+
+    // Sub stages don't all follow a naming convention, so only the ones starting with the kingdom
+    // prefix of their home stage (e.g. WaterfallWorld for Cascade) are recognized
+    pub fn from_raw(raw: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|stage| {
+            let home = stage.to_str();
+            let prefix = &home[..home.find("World").unwrap_or(home.len())];
+
+            raw == home || raw.starts_with(&format!("{}World", prefix))
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Cap => "Cap",
+            Self::Cascade => "Cascade",
+            Self::Sand => "Sand",
+            Self::Lake => "Lake",
+            Self::Wooded => "Wooded",
+            Self::Cloud => "Cloud",
+            Self::Lost => "Lost",
+            Self::Metro => "Metro",
+            Self::Sea => "Sea",
+            Self::Snow => "Snow",
+            Self::Lunch => "Lunch",
+            Self::Ruined => "Ruined",
+            Self::Bowser => "Bowser",
+            Self::Moon => "Moon",
+            Self::Mush => "Mush",
+            Self::Dark => "Dark",
+            Self::Darker => "Darker",
+        }
+    }
+
+    // Friendly name of a raw stage id, or the raw id when the kingdom is unknown
+    pub fn display_raw(raw: &str) -> String {
+        match Self::from_raw(raw) {
+            Some(stage) if stage.to_str() == raw => stage.name().to_owned(),
+            Some(stage) => format!("{} ({})", stage.name(), raw),
+            None => raw.to_owned(),
+        }
+    }
+
     pub fn to_str(&self) -> &'static str {
         match self {
             Self::Cap => "CapWorldHomeStage",
@@ -119,7 +188,7 @@ Here is the list of the valid stages
             Self::Moon => "MoonWorldHomeStage",
             Self::Mush => "PeachWorldHomeStage",
             Self::Dark => "Special1WorldHomeStage",
-            Self::Darker => "Special2WorldHomeStag",
+            Self::Darker => "Special2WorldHomeStage",
         }
     }
 }
@@ -186,6 +255,11 @@ pub enum TagSubCmd {
         minutes: u16,
         seconds: u8,
     },
+    // Negative to remove time, see tag subtime
+    AddTime {
+        username: String,
+        seconds: i64,
+    },
     Seeking {
         username: String,
         state: TagState,
@@ -194,6 +268,10 @@ pub enum TagSubCmd {
         time: u8,
         seekers: Vec<String>,
     },
+    Leaderboard,
+    Stop,
+    Pause,
+    Resume,
 }
 
 #[derive(Debug)]
@@ -208,15 +286,73 @@ pub enum FlipSubCmd {
 #[derive(Debug)]
 pub enum ShineSubCmd {
     List,
-    Clear,
+    // Destructive, only done if the operator typed confirm
+    Clear { confirmed: bool },
     Sync,
     Send { id: i32, players: Vec<String> },
+    Profile { name: String },
+    Push { id: i32, is_grand: bool },
+    Dump { path: String },
+    Count,
+}
+
+#[derive(Debug)]
+pub enum ScenarioSubCmd {
+    Merge { enabled: bool },
+    GetStage { stage: Stage },
+    // None removes the override, the stage follows scenario merge again
+    SetStage { stage: Stage, enabled: Option<bool> },
+}
+
+#[derive(Debug)]
+pub enum HideSubCmd {
+    Add { target: String, observer: String },
+    Remove { target: String, observer: String },
+    List,
+    Clear,
+}
+
+#[derive(Debug)]
+pub enum CostumeSubCmd {
+    Reset {
+        players: Vec<String>,
+    },
+    Set {
+        player: String,
+        body: String,
+        cap: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum BanTarget {
+    Id(Uuid),
+    Ip(IpNet),
+}
+
+impl FromStr for BanTarget {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = Uuid::parse_str(s) {
+            return Ok(Self::Id(id));
+        }
+
+        parse_ip_range(s)
+            .map(Self::Ip)
+            .map_err(|_| "Expected a player id, an ip or a cidr range")
+    }
 }
 
 #[derive(Debug)]
 pub enum Command {
     Rejoin {
         players: Vec<String>,
+        // Time between each disconnection, so everyone doesn't reconnect at the same time
+        delay: Option<Duration>,
+    },
+    Kick {
+        players: Vec<String>,
     },
     Crash {
         players: Vec<String>,
@@ -224,6 +360,28 @@ pub enum Command {
     Ban {
         players: Vec<String>,
     },
+    Unban {
+        target: BanTarget,
+    },
+    BanIp {
+        range: IpNet,
+    },
+    Allow {
+        id: Uuid,
+    },
+    Unallow {
+        id: Uuid,
+    },
+    Spectate {
+        id: Uuid,
+    },
+    Unspectate {
+        id: Uuid,
+    },
+    Peers,
+    Drop {
+        id: Uuid,
+    },
     Send {
         stage: Stage,
         id: String,
@@ -233,15 +391,72 @@ pub enum Command {
     SendAll {
         stage: Stage,
     },
+    SendStage {
+        from: Stage,
+        to: Stage,
+        scenario: i8,
+    },
+    Scatter {
+        stages: Vec<Stage>,
+        // Random when not given, logged so the same scatter can be done again
+        seed: Option<u64>,
+    },
     Scenario {
-        subcmd: String,
-        value: String,
+        subcmd: ScenarioSubCmd,
     },
     MaxPlayers {
-        count: u16,
+        count: i16,
     },
     List,
+    Locations,
+    Who,
+    Lookup {
+        player: String,
+    },
+    Rename {
+        id: Uuid,
+        name: String,
+    },
+    Stats,
+    Announce {
+        message: String,
+    },
+    Whisper {
+        player: String,
+        message: String,
+    },
+    Pause {
+        paused: bool,
+    },
+    Resync {
+        player: Option<String>,
+    },
+    Freeze {
+        player: String,
+    },
+    Unfreeze {
+        player: String,
+    },
+    Teleport {
+        from: String,
+        to: String,
+    },
     LoadSettings,
+    Restart,
+    Save {
+        slot: String,
+    },
+    Load {
+        slot: String,
+    },
+    Profiles,
+    Get {
+        path: String,
+    },
+    Set {
+        path: String,
+        value: String,
+    },
     Tag {
         subcmd: TagSubCmd,
     },
@@ -251,6 +466,12 @@ pub enum Command {
     Shine {
         subcmd: ShineSubCmd,
     },
+    Costume {
+        subcmd: CostumeSubCmd,
+    },
+    Hide {
+        subcmd: HideSubCmd,
+    },
     Stop,
     Unknown {
         cmd: String,
@@ -275,7 +496,19 @@ impl Command {
 
         let cmd = splitted.remove(0);
 
-        if splitted.is_empty() && (cmd != "list" && cmd != "stop" && cmd != "loadsettings") {
+        if splitted.is_empty()
+            && (cmd != "list"
+                && cmd != "locations"
+                && cmd != "resync"
+                && cmd != "who"
+                && cmd != "stats"
+                && cmd != "stop"
+                && cmd != "loadsettings"
+                && cmd != "restart"
+                && cmd != "profiles"
+                && cmd != "peers"
+                && cmd != "scatter")
+        {
             let cmd = Self::default_from_str(cmd);
             return match &cmd {
                 Self::Unknown { cmd: _ } => Ok(cmd),
@@ -284,7 +517,26 @@ impl Command {
         }
 
         let parsed = match cmd {
-            "rejoin" => Self::Rejoin {
+            "rejoin" => {
+                let delay = match splitted.iter().position(|arg| *arg == "--delay") {
+                    Some(i) if i + 1 < splitted.len() => {
+                        let delay = parse_delay(splitted[i + 1])?;
+                        splitted.drain(i..=i + 1);
+
+                        Some(delay)
+                    }
+                    Some(_) => return Err(Self::default_from_str("rejoin").help().to_string()),
+                    None => None,
+                };
+
+                Self::Rejoin {
+                    players: Self::wildcard_filter(
+                        splitted.iter().map(|s| s.to_lowercase()).collect(),
+                    ),
+                    delay,
+                }
+            }
+            "kick" => Self::Kick {
                 players: Self::wildcard_filter(splitted.iter().map(|s| s.to_lowercase()).collect()),
             },
             "crash" => Self::Crash {
@@ -293,9 +545,63 @@ impl Command {
             "ban" => Self::Ban {
                 players: Self::wildcard_filter(splitted.iter().map(|s| s.to_lowercase()).collect()),
             },
+            "unban" => Self::Unban {
+                target: BanTarget::from_str(splitted.remove(0))?,
+            },
+            "banip" => Self::BanIp {
+                range: parse_ip_range(splitted.remove(0))?,
+            },
+            "allow" => Self::Allow {
+                id: Uuid::parse_str(splitted.remove(0)).map_err(|_| "Invalid user id")?,
+            },
+            "unallow" => Self::Unallow {
+                id: Uuid::parse_str(splitted.remove(0)).map_err(|_| "Invalid user id")?,
+            },
+            "spectate" => Self::Spectate {
+                id: Uuid::parse_str(splitted.remove(0)).map_err(|_| "Invalid user id")?,
+            },
+            "unspectate" => Self::Unspectate {
+                id: Uuid::parse_str(splitted.remove(0)).map_err(|_| "Invalid user id")?,
+            },
+            "peers" => Self::Peers,
+            "drop" => Self::Drop {
+                id: Uuid::parse_str(splitted.remove(0)).map_err(|_| "Invalid user id")?,
+            },
             "sendall" => Self::SendAll {
                 stage: Stage::from_str(splitted.remove(0))?,
             },
+            "sendstage" => match splitted.as_slice() {
+                [from, to, scenario] => Self::SendStage {
+                    from: Stage::from_str(from)?,
+                    to: Stage::from_str(to)?,
+                    scenario: scenario
+                        .parse::<i8>()
+                        .map_err(|_| "Scenario should be a number between -1 and 127".to_owned())?,
+                },
+                _ => return Err(Self::default_from_str("sendstage").help().to_string()),
+            },
+            "scatter" => {
+                let seed = match splitted.iter().position(|arg| *arg == "--seed") {
+                    Some(i) if i + 1 < splitted.len() => {
+                        let seed = splitted[i + 1]
+                            .parse()
+                            .map_err(|_| "Invalid seed, expected a positive integer")?;
+                        splitted.drain(i..=i + 1);
+
+                        Some(seed)
+                    }
+                    Some(_) => return Err(Self::default_from_str("scatter").help().to_string()),
+                    None => None,
+                };
+
+                Self::Scatter {
+                    stages: splitted
+                        .into_iter()
+                        .map(Stage::from_str)
+                        .collect::<Result<_, _>>()?,
+                    seed,
+                }
+            }
             "send" if splitted.len() < 4 => {
                 return Err(Self::default_from_str("send").help().to_string());
             }
@@ -308,12 +614,31 @@ impl Command {
                     .map_err(|_| "Scenario should be a number between -1 and 127".to_owned())?,
                 players: Self::wildcard_filter(splitted.iter().map(ToString::to_string).collect()),
             },
-            "scenario" if splitted.len() < 2 => {
-                return Err(Self::default_from_str("scenario").help().to_string());
-            }
-            "scenario" => Self::Scenario {
-                subcmd: splitted.remove(0).to_owned(),
-                value: splitted.remove(0).to_owned(),
+            "scenario" => match splitted.as_slice() {
+                ["merge", value] => Self::Scenario {
+                    subcmd: ScenarioSubCmd::Merge {
+                        enabled: value.parse().map_err(|_| {
+                            format!("Invalid value '{}', expected true or false", value)
+                        })?,
+                    },
+                },
+                ["stage", stage] => Self::Scenario {
+                    subcmd: ScenarioSubCmd::GetStage {
+                        stage: Stage::from_str(stage)?,
+                    },
+                },
+                ["stage", stage, value] => Self::Scenario {
+                    subcmd: ScenarioSubCmd::SetStage {
+                        stage: Stage::from_str(stage)?,
+                        enabled: match *value {
+                            "reset" => None,
+                            value => Some(value.parse().map_err(|_| {
+                                format!("Invalid value '{}', expected true, false or reset", value)
+                            })?),
+                        },
+                    },
+                },
+                _ => return Err(Self::default_from_str("scenario").help().to_string()),
             },
             "maxplayers" if splitted.is_empty() => {
                 return Err(Self::default_from_str("maxplayers").help().to_string());
@@ -321,13 +646,70 @@ impl Command {
             "maxplayers" => Self::MaxPlayers {
                 count: splitted
                     .remove(0)
-                    .parse::<u16>()
-                    .map_err(|_| "Count should be a positive integer")?,
+                    .parse::<i16>()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or(format!(
+                        "Count should be an integer between 1 and {}",
+                        i16::MAX
+                    ))?,
             },
             "list" => Self::List,
-            "tag" if splitted.len() < 4 => {
-                return Err(Self::default_from_str("tag").help().to_string());
+            "locations" => Self::Locations,
+            "who" => Self::Who,
+            "lookup" => Self::Lookup {
+                player: splitted.remove(0).to_owned(),
+            },
+            "rename" => match splitted.as_slice() {
+                [id, name] => Self::Rename {
+                    id: Uuid::parse_str(id).map_err(|_| "Invalid user id")?,
+                    name: (*name).to_owned(),
+                },
+                _ => return Err(Self::default_from_str("rename").help().to_string()),
+            },
+            "stats" => Self::Stats,
+            "announce" => Self::Announce {
+                message: splitted.join(" "),
+            },
+            "whisper" if splitted.len() < 2 => {
+                return Err(Self::default_from_str("whisper").help().to_string());
+            }
+            "whisper" => Self::Whisper {
+                player: splitted.remove(0).to_owned(),
+                message: splitted.join(" "),
+            },
+            "resync" => Self::Resync {
+                player: splitted.first().map(|player| (*player).to_owned()),
+            },
+            "freeze" => Self::Freeze {
+                player: splitted.remove(0).to_owned(),
+            },
+            "unfreeze" => Self::Unfreeze {
+                player: splitted.remove(0).to_owned(),
+            },
+            "tp" if splitted.len() != 2 => {
+                return Err(Self::default_from_str("tp").help().to_string());
+            }
+            "tp" => Self::Teleport {
+                from: splitted.remove(0).to_owned(),
+                to: splitted.remove(0).to_owned(),
+            },
+            "pause" => Self::Pause {
+                paused: splitted
+                    .remove(0)
+                    .parse()
+                    .map_err(|_| "Invalid value, expected true or false")?,
+            },
+            "get" => Self::Get {
+                path: splitted.remove(0).to_owned(),
+            },
+            "set" if splitted.len() < 2 => {
+                return Err(Self::default_from_str("set").help().to_string());
             }
+            "set" => Self::Set {
+                path: splitted.remove(0).to_owned(),
+                value: splitted.join(" "),
+            },
             "tag" => {
                 let subcmd = splitted.remove(0);
 
@@ -343,6 +725,24 @@ impl Command {
                             })?,
                         },
                     },
+                    "addtime" | "subtime" if splitted.len() == 2 => {
+                        let username = splitted.remove(0).to_owned();
+                        let seconds =
+                            i64::from(splitted.remove(0).parse::<u32>().map_err(|_| {
+                                "Invalid seconds, value should be a positive integer"
+                            })?);
+
+                        Self::Tag {
+                            subcmd: TagSubCmd::AddTime {
+                                username,
+                                seconds: if subcmd == "subtime" {
+                                    -seconds
+                                } else {
+                                    seconds
+                                },
+                            },
+                        }
+                    }
                     "seeking" if splitted.len() == 2 => Self::Tag {
                         subcmd: TagSubCmd::Seeking {
                             username: splitted.remove(0).to_owned(),
@@ -358,6 +758,18 @@ impl Command {
                             },
                         },
                     },
+                    "leaderboard" if splitted.is_empty() => Self::Tag {
+                        subcmd: TagSubCmd::Leaderboard,
+                    },
+                    "stop" if splitted.is_empty() => Self::Tag {
+                        subcmd: TagSubCmd::Stop,
+                    },
+                    "pause" if splitted.is_empty() => Self::Tag {
+                        subcmd: TagSubCmd::Pause,
+                    },
+                    "resume" if splitted.is_empty() => Self::Tag {
+                        subcmd: TagSubCmd::Resume,
+                    },
                     "start" if splitted.len() >= 2 => Self::Tag {
                         subcmd: TagSubCmd::Start {
                             time: splitted
@@ -381,8 +793,16 @@ impl Command {
                 },
                 "add" if splitted.len() == 1 => Command::Flip {
                     subcmd: FlipSubCmd::Add {
-                        user_id: Uuid::from_str(splitted.remove(0))
-                            .map_err(|_| "Invalid player id")?,
+                        user_id: match Uuid::from_str(splitted.remove(0)) {
+                            Ok(id) if id.is_nil() => {
+                                return Err(
+                                    "The nil id is used by the server and can't be flipped"
+                                        .to_owned(),
+                                );
+                            }
+                            Ok(id) => id,
+                            Err(_) => return Err("Invalid player id".to_owned()),
+                        },
                     },
                 },
                 "remove" if splitted.len() == 1 => Command::Flip {
@@ -413,11 +833,21 @@ impl Command {
                     subcmd: ShineSubCmd::List,
                 },
                 "clear" => Self::Shine {
-                    subcmd: ShineSubCmd::Clear,
+                    subcmd: ShineSubCmd::Clear {
+                        confirmed: splitted.first() == Some(&"confirm"),
+                    },
                 },
                 "sync" => Self::Shine {
                     subcmd: ShineSubCmd::Sync,
                 },
+                "count" => Self::Shine {
+                    subcmd: ShineSubCmd::Count,
+                },
+                "dump" if splitted.len() == 1 => Self::Shine {
+                    subcmd: ShineSubCmd::Dump {
+                        path: splitted.remove(0).to_owned(),
+                    },
+                },
                 "send" if splitted.len() >= 2 => Self::Shine {
                     subcmd: ShineSubCmd::Send {
                         id: splitted
@@ -429,21 +859,103 @@ impl Command {
                         ),
                     },
                 },
+                "push" if matches!(splitted.as_slice(), [_] | [_, "grand"]) => Self::Shine {
+                    subcmd: ShineSubCmd::Push {
+                        id: splitted
+                            .remove(0)
+                            .parse()
+                            .map_err(|_| "Invalid moon id, it should be a number")?,
+                        is_grand: !splitted.is_empty(),
+                    },
+                },
+                "profile" if splitted.len() == 1 => Self::Shine {
+                    subcmd: ShineSubCmd::Profile {
+                        name: splitted.remove(0).to_owned(),
+                    },
+                },
                 _ => return Err(Self::default_from_str("shine").help().to_string()),
             },
+            "costume" => match splitted.as_slice() {
+                ["reset", players @ ..] if !players.is_empty() => Self::Costume {
+                    subcmd: CostumeSubCmd::Reset {
+                        players: Self::wildcard_filter(
+                            players.iter().map(|player| (*player).to_owned()).collect(),
+                        ),
+                    },
+                },
+                [player, body, cap] => Self::Costume {
+                    subcmd: CostumeSubCmd::Set {
+                        player: (*player).to_owned(),
+                        body: (*body).to_owned(),
+                        cap: (*cap).to_owned(),
+                    },
+                },
+                _ => return Err(Self::default_from_str("costume").help().to_string()),
+            },
+            "hide" => match splitted.as_slice() {
+                ["list"] => Self::Hide {
+                    subcmd: HideSubCmd::List,
+                },
+                ["clear"] => Self::Hide {
+                    subcmd: HideSubCmd::Clear,
+                },
+                [target, "from", observer] => Self::Hide {
+                    subcmd: HideSubCmd::Add {
+                        target: (*target).to_owned(),
+                        observer: (*observer).to_owned(),
+                    },
+                },
+                ["remove", target, "from", observer] => Self::Hide {
+                    subcmd: HideSubCmd::Remove {
+                        target: (*target).to_owned(),
+                        observer: (*observer).to_owned(),
+                    },
+                },
+                _ => return Err(Self::default_from_str("hide").help().to_string()),
+            },
             "stop" => Self::Stop,
             "loadsettings" => Self::LoadSettings,
+            "restart" => Self::Restart,
+            "save" => Self::Save {
+                slot: splitted.remove(0).to_owned(),
+            },
+            "load" => Self::Load {
+                slot: splitted.remove(0).to_owned(),
+            },
+            "profiles" => Self::Profiles,
             v => Self::Unknown { cmd: v.to_owned() },
         };
 
         Ok(parsed)
     }
 
+    // Unknown commands only print the help
+    #[inline]
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, Self::Unknown { cmd: _ })
+    }
+
     pub fn default_from_str(string: &str) -> Self {
         match string {
-            "rejoin" => Self::Rejoin { players: vec![] },
+            "rejoin" => Self::Rejoin {
+                players: vec![],
+                delay: None,
+            },
+            "kick" => Self::Kick { players: vec![] },
             "crash" => Self::Crash { players: vec![] },
             "ban" => Self::Ban { players: vec![] },
+            "unban" => Self::Unban {
+                target: BanTarget::Id(Uuid::nil()),
+            },
+            "banip" => Self::BanIp {
+                range: IpNet::default(),
+            },
+            "allow" => Self::Allow { id: Uuid::nil() },
+            "unallow" => Self::Unallow { id: Uuid::nil() },
+            "spectate" => Self::Spectate { id: Uuid::nil() },
+            "unspectate" => Self::Unspectate { id: Uuid::nil() },
+            "peers" => Self::Peers,
+            "drop" => Self::Drop { id: Uuid::nil() },
             "send" => Self::Send {
                 stage: Stage::Cap,
                 id: "".to_owned(),
@@ -451,13 +963,65 @@ impl Command {
                 players: vec![],
             },
             "sendall" => Self::SendAll { stage: Stage::Cap },
+            "sendstage" => Self::SendStage {
+                from: Stage::Cap,
+                to: Stage::Cap,
+                scenario: 0,
+            },
+            "scatter" => Self::Scatter {
+                stages: vec![],
+                seed: None,
+            },
             "scenario" => Self::Scenario {
-                subcmd: "".to_owned(),
-                value: "".to_owned(),
+                subcmd: ScenarioSubCmd::Merge { enabled: false },
             },
             "maxplayers" => Self::MaxPlayers { count: 0 },
             "list" => Self::List,
+            "locations" => Self::Locations,
+            "who" => Self::Who,
+            "lookup" => Self::Lookup {
+                player: "".to_owned(),
+            },
+            "rename" => Self::Rename {
+                id: Uuid::nil(),
+                name: "".to_owned(),
+            },
+            "stats" => Self::Stats,
+            "announce" => Self::Announce {
+                message: "".to_owned(),
+            },
+            "whisper" => Self::Whisper {
+                player: "".to_owned(),
+                message: "".to_owned(),
+            },
+            "pause" => Self::Pause { paused: false },
+            "resync" => Self::Resync { player: None },
+            "freeze" => Self::Freeze {
+                player: "".to_owned(),
+            },
+            "unfreeze" => Self::Unfreeze {
+                player: "".to_owned(),
+            },
+            "tp" => Self::Teleport {
+                from: "".to_owned(),
+                to: "".to_owned(),
+            },
             "loadsettings" => Self::LoadSettings,
+            "restart" => Self::Restart,
+            "save" => Self::Save {
+                slot: "".to_owned(),
+            },
+            "load" => Self::Load {
+                slot: "".to_owned(),
+            },
+            "profiles" => Self::Profiles,
+            "get" => Self::Get {
+                path: "".to_owned(),
+            },
+            "set" => Self::Set {
+                path: "".to_owned(),
+                value: "".to_owned(),
+            },
             "tag" => Self::Tag {
                 subcmd: TagSubCmd::Seeking {
                     username: "".to_owned(),
@@ -470,6 +1034,12 @@ impl Command {
             "shine" => Self::Shine {
                 subcmd: ShineSubCmd::List,
             },
+            "costume" => Self::Costume {
+                subcmd: CostumeSubCmd::Reset { players: vec![] },
+            },
+            "hide" => Self::Hide {
+                subcmd: HideSubCmd::List,
+            },
             "stop" => Self::Stop,
             v => Self::Unknown { cmd: v.to_owned() },
         }
@@ -477,9 +1047,16 @@ impl Command {
 
     pub fn help(&self) -> Help {
         match self {
-            Self::Rejoin { players: _ } => Help::new(
-                "rejoin <username 1|*> <username 2> ...",
-                "Will force player to disconnect and reconnect",
+            Self::Rejoin {
+                players: _,
+                delay: _,
+            } => Help::new(
+                "rejoin <username 1|*> <username 2> ... [--delay <ms|Nms|Ns>]",
+                "Will force player to disconnect and reconnect. With a delay, players are disconnected one by one",
+            ),
+            Self::Kick { players: _ } => Help::new(
+                "kick <username 1|*> <username 2> ...",
+                "Will disconnect player and prevent them from reconnecting for server.kick_cooldown_secs",
             ),
             Self::Crash { players: _ } => {
                 Help::new("crash <username 1|*> <username 2> ...", "Will crash player")
@@ -487,6 +1064,19 @@ impl Command {
             Self::Ban { players: _ } => {
                 Help::new("ban <username 1|*> <username 2> ...", "Will ban player")
             }
+            Self::Unban { target: _ } => {
+                Help::new("unban <id|ip|cidr>", "Will remove the id or the ip range from the ban list")
+            }
+            Self::BanIp { range: _ } => Help::new(
+                "banip <ip|cidr>",
+                "Will ban every ip in the range, like 192.168.1.0/24, and disconnect the players using them",
+            ),
+            Self::Allow { id: _ } => Help::new("allow <user id>", "Will add the player to the allow list, only used if allow_list.enabled is true"),
+            Self::Unallow { id: _ } => Help::new("unallow <user id>", "Will remove the player from the allow list"),
+            Self::Spectate { id: _ } => Help::new("spectate <user id>", "Will make the player join as a spectator, receiving everything without being seen or taking a slot"),
+            Self::Unspectate { id: _ } => Help::new("unspectate <user id>", "Will make the spectator join as a player again"),
+            Self::Peers => Help::new("peers", "List every connection with its user id, ip and whether it's still connected. Ips are hidden if server.hide_ips is true"),
+            Self::Drop { id: _ } => Help::new("drop <user id>", "Will disconnect the connection of this user id"),
             Self::Send {
                 stage: _,
                 id: _,
@@ -500,29 +1090,88 @@ impl Command {
                 "sendall <stage> ",
                 "Will teleport players to the wanted stage",
             ),
-            Self::Scenario {
-                subcmd: _,
-                value: _,
-            } => Help::new("scenario merge <true|false>", "Will merge scenarios"),
+            Self::SendStage {
+                from: _,
+                to: _,
+                scenario: _,
+            } => Help::new(
+                "sendstage <from stage> <to stage> <scenario[-1..127]>",
+                "Will teleport the players in the first stage to the second one",
+            ),
+            Self::Scatter { stages: _, seed: _ } => Help::new(
+                "scatter [--seed <number>] [stage 1] [stage 2] ...",
+                "Will teleport each player to a random stage of the list, or of every stage if none is given. The same seed gives the same stages",
+            ),
+            Self::Scenario { subcmd: _ } => {
+                let merge = "scenario merge <true|false>";
+                let merge_desc = format!("- {} will merge scenarios", "scenario merge".cyan());
+
+                let stage = "scenario stage <stage> [true|false|reset]";
+                let stage_desc = format!("- {} show or override scenario merge in a stage, reset follows scenario merge again", "scenario stage".cyan());
+
+                Help::new(
+                    &format!("{}\n{}", merge, stage),
+                    &format!("{}\n{}", merge_desc, stage_desc)
+                )
+            },
             Self::MaxPlayers { count: _ } => Help::new(
                 "maxplayers <count>",
                 "Will update the max player that can connect to the server",
             ),
             Self::List => Help::new("list", "List all the connected players"),
+            Self::Locations => Help::new("locations", "List the connected players grouped by stage"),
+            Self::Who => Help::new("who", "List the connected players with their ip, connection time and stage. Ips are hidden if server.hide_ips is true"),
+            Self::Lookup { player: _ } => Help::new("lookup <username|user id>", "Show when a player that joined the server was last seen"),
+            Self::Rename { id: _, name: _ } => Help::new("rename <user id> <new name>", "Will change the name of the player for everyone"),
+            Self::Stats => Help::new("stats", "Show the number of rejected connections by reason"),
+            Self::Announce { message: _ } => Help::new("announce <message>", "Will display the message to everyone, only clients supporting chat messages (type 12) show it"),
+            Self::Whisper { player: _, message: _ } => Help::new("whisper <username> <message>", "Will display the message to a player, only clients supporting chat messages (type 12) show it"),
+            Self::Pause { paused: _ } => Help::new("pause <true|false>", "Will stop relaying the packets of the players. Their state is still updated and sent to everyone when resuming"),
+            Self::Resync { player: _ } => Help::new("resync [username]", "Will send the last known state of everyone (or of a player) to the others, useful when they are out of sync"),
+            Self::Freeze { player: _ } => Help::new("freeze <username>", "Will show the player at their current position to everyone until unfreeze"),
+            Self::Unfreeze { player: _ } => Help::new("unfreeze <username>", "Will show the movement of a frozen player again"),
+            Self::Teleport { from: _, to: _ } => Help::new("tp <from username> <to username>", "Will send the first player to the stage and scenario of the second one"),
             Self::LoadSettings => Help::new("loadsettings", "Load the settings into the server. Do ift after changing the settings while the server is running"),
+            Self::Restart => Help::new("restart", "Load the settings and listen on the new address or port if they changed, without losing the moons or disconnecting the players"),
+            Self::Save { slot: _ } => Help::new("save <profile>", "Will save the current settings to profiles/<profile>.json"),
+            Self::Load { slot: _ } => Help::new("load <profile>", "Will apply and save the settings of a profile. The address, the port and the ban list are kept"),
+            Self::Profiles => Help::new("profiles", "List the saved settings profiles"),
+            Self::Get { path: _ } => Help::new("get <path>", "Will print the value of a setting, e.g. get server.max_players"),
+            Self::Set { path: _, value: _ } => Help::new(
+                "set <path> <value>",
+                "Will update a setting and save it, e.g. set server.max_players 16. server.address and server.port are updated in settings.json then applied with restart",
+            ),
             Self::Tag { subcmd: _ } => {
                 let time_usage = "tag time <username|*> <mintues[0-65535]> <seconds[0-59]>";
                 let time_desc = format!("- {} set the time for 1 player or everyone if username is *", "tag time".cyan());
 
+                let addtime = "tag addtime <username> <seconds>";
+                let addtime_desc = format!("- {} add seconds to the time of the player", "tag addtime".cyan());
+
+                let subtime = "tag subtime <username> <seconds>";
+                let subtime_desc = format!("- {} remove seconds from the time of the player, it won't go below 0", "tag subtime".cyan());
+
                 let seeking = "tag seeking <username|*> <hider|seeker>";
                 let seeking_desc = format!("- {} allows to set the player as a hider or seeker. You can set everyone role if the username is *", "tag seeking".cyan());
 
                 let start = "tag start <time[0-255]> <username 1> <username 2> ...";
                 let start_desc = format!("- {} will start the game after the input time is over and set the input players to seeker and the rest to hider", "tag start".cyan());
 
+                let leaderboard = "tag leaderboard";
+                let leaderboard_desc = format!("- {} list the connected players sorted by their time", "tag leaderboard".cyan());
+
+                let stop = "tag stop";
+                let stop_desc = format!("- {} will cancel the pending start and set everyone to hider", "tag stop".cyan());
+
+                let pause = "tag pause";
+                let pause_desc = format!("- {} will freeze the countdown of tag start", "tag pause".cyan());
+
+                let resume = "tag resume";
+                let resume_desc = format!("- {} will continue the countdown from where it was paused", "tag resume".cyan());
+
                 Help::new(
-                    &format!("{}\n{}\n{}", time_usage, seeking, start),
-                    &format!("{}\n{}\n{}", time_desc, seeking_desc, start_desc)
+                    &format!("{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}", time_usage, addtime, subtime, seeking, start, leaderboard, stop, pause, resume),
+                    &format!("{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}", time_desc, addtime_desc, subtime_desc, seeking_desc, start_desc, leaderboard_desc, stop_desc, pause_desc, resume_desc)
                 )
             },
             Self::Flip { subcmd: _ } => {
@@ -551,8 +1200,8 @@ impl Command {
                 let list = "shine list";
                 let list_desc = format!("- {} list the ids of the collected moons", "shine list".cyan());
 
-                let clear = "shine clear";
-                let clear_desc = format!("- {} will delete all the collected moons", "shine clean".cyan());
+                let clear = "shine clear confirm";
+                let clear_desc = format!("- {} will delete all the collected moons", "shine clear".cyan());
 
                 let sync = "shine sync";
                 let sync_desc = format!("- {} will force the sync of the moons", "shine sync".cyan());
@@ -561,90 +1210,406 @@ impl Command {
                 let send_desc = format!("- {} will send a moon to a player or everyone if username is *", "shine send".cyan());
 
 
+                let push = "shine push <id> [grand]";
+                let push_desc = format!("- {} will add a moon to the collected ones and send it to everyone, add grand for a multi moon", "shine push".cyan());
+
+                let profile = "shine profile <name>";
+                let profile_desc = format!("- {} will save the moons and load the ones of the profile. Profiles are set in persist_shines.profiles, default is persist_shines.file_name", "shine profile".cyan());
+
+                let count = "shine count";
+                let count_desc = format!("- {} will print the number of collected moons", "shine count".cyan());
+
+                let dump = "shine dump <path>";
+                let dump_desc = format!("- {} will save the collected moons to a file, even if persist_shines is disabled", "shine dump".cyan());
+
+                Help::new(
+                    &format!("{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}", list, clear, sync, send, push, profile, count, dump),
+                    &format!("{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}", list_desc, clear_desc, sync_desc, send_desc, push_desc, profile_desc, count_desc, dump_desc)
+                )
+            },
+            Self::Costume { subcmd: _ } => {
+                let reset = "costume reset <username 1|*> <username 2> ...";
+                let reset_desc = format!("- {} will reset the costume of the players to the default one", "costume reset".cyan());
+
+                let set = "costume <username|*> <body> <cap>";
+                let set_desc = format!("- {} will force a costume on the player, e.g. costume * Mario Mario", "costume".cyan());
+
+                Help::new(
+                    &format!("{}\n{}", reset, set),
+                    &format!("{}\n{}", reset_desc, set_desc)
+                )
+            },
+            Self::Hide { subcmd: _ } => {
+                let add = "hide <target> from <observer>";
+                let add_desc = format!("- {} the observer won't see the movement of the target", "hide".cyan());
+
+                let remove = "hide remove <target> from <observer>";
+                let remove_desc = format!("- {} will remove a hide rule", "hide remove".cyan());
+
+                let list = "hide list";
+                let list_desc = format!("- {} list the hide rules", "hide list".cyan());
+
+                let clear = "hide clear";
+                let clear_desc = format!("- {} will remove all the hide rules", "hide clear".cyan());
+
                 Help::new(
-                    &format!("{}\n{}\n{}\n{}", list, clear, sync, send),
-                    &format!("{}\n{}\n{}\n{}", list_desc, clear_desc, sync_desc, send_desc)
+                    &format!("{}\n{}\n{}\n{}", add, remove, list, clear),
+                    &format!("{}\n{}\n{}\n{}", add_desc, remove_desc, list_desc, clear_desc)
                 )
             },
             Self::Stop => Help::new("stop", "Will stop the server"),
             Self::Unknown { cmd: _ } => Help::merge(vec![
                 Self::default_from_str("rejoin").help(),
+                Self::default_from_str("kick").help(),
                 Self::default_from_str("crash").help(),
                 Self::default_from_str("ban").help(),
+                Self::default_from_str("unban").help(),
+                Self::default_from_str("banip").help(),
+                Self::default_from_str("allow").help(),
+                Self::default_from_str("unallow").help(),
+                Self::default_from_str("spectate").help(),
+                Self::default_from_str("unspectate").help(),
+                Self::default_from_str("peers").help(),
+                Self::default_from_str("drop").help(),
                 Self::default_from_str("send").help(),
                 Self::default_from_str("sendall").help(),
+                Self::default_from_str("sendstage").help(),
+                Self::default_from_str("scatter").help(),
                 Self::default_from_str("scenario").help(),
                 Self::default_from_str("maxplayers").help(),
                 Self::default_from_str("list").help(),
+                Self::default_from_str("locations").help(),
+                Self::default_from_str("who").help(),
+                Self::default_from_str("lookup").help(),
+                Self::default_from_str("rename").help(),
+                Self::default_from_str("stats").help(),
+                Self::default_from_str("announce").help(),
+                Self::default_from_str("whisper").help(),
+                Self::default_from_str("pause").help(),
+                Self::default_from_str("resync").help(),
+                Self::default_from_str("freeze").help(),
+                Self::default_from_str("unfreeze").help(),
+                Self::default_from_str("tp").help(),
                 Self::default_from_str("loadsettings").help(),
+                Self::default_from_str("restart").help(),
+                Self::default_from_str("save").help(),
+                Self::default_from_str("load").help(),
+                Self::default_from_str("profiles").help(),
+                Self::default_from_str("get").help(),
+                Self::default_from_str("set").help(),
                 Self::default_from_str("tag").help(),
                 Self::default_from_str("flip").help(),
                 Self::default_from_str("shine").help(),
+                Self::default_from_str("costume").help(),
+                Self::default_from_str("hide").help(),
                 Self::default_from_str("stop").help(),
             ]),
         }
     }
 }
 
-pub async fn listen(server: Arc<Server>) {
-    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+// Accepts 200ms, 2s or 200 (milliseconds)
+fn parse_delay(value: &str) -> Result<Duration, String> {
+    let error = || format!("Invalid delay {}, expected e.g. 200ms or 2s", value);
+
+    if let Some(ms) = value.strip_suffix("ms") {
+        ms.parse().map(Duration::from_millis).map_err(|_| error())
+    } else if let Some(s) = value.strip_suffix('s') {
+        s.parse().map(Duration::from_secs).map_err(|_| error())
+    } else {
+        value
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| error())
+    }
+}
 
-    let task = async move {
-        loop {
-            let line = stdin.next_line().await;
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
 
-            if line.is_err() {
-                error!("Failed to read stdin {}", line.unwrap_err());
-                continue;
-            }
+    match (seconds / 3600, (seconds % 3600) / 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
 
-            let line = line.unwrap();
+fn format_table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let widths: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain([header.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let headers = headers.iter().map(|h| (*h).to_owned()).collect();
+
+    [headers]
+        .into_iter()
+        .chain(rows)
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join(" | ")
+                .trim_end()
+                .to_owned()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
 
-            if let Some(line) = line {
-                match Command::parse(line) {
-                    Ok(cmd) => exec_cmd(server.clone(), cmd).await,
-                    Err(message) => println!("\n{}\n{}", "[Error]".red(), message),
-                };
-            }
-        }
-    };
+// Sent by the admin api next to the text output, so tools don't have to parse the tables
+#[derive(Debug, Serialize)]
+#[cfg_attr(test, derive(serde::Deserialize, PartialEq))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CommandResult {
+    Players { players: Vec<ListedPlayer> },
+    Banned { ids: Vec<Uuid> },
+    Announced { delivered: usize },
+    Leaderboard { players: Vec<TagTime> },
+}
 
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            info!("Stopping the server");
-            exit(0);
-        },
-        _ = task => {}
-    };
+#[derive(Debug, Serialize)]
+#[cfg_attr(test, derive(serde::Deserialize, PartialEq))]
+pub struct ListedPlayer {
+    pub id: Uuid,
+    pub name: String,
+    pub captured: Option<String>,
+    pub moons: usize,
+    pub stage: Option<String>,
+    pub is_seeking: bool,
 }
 
-async fn exec_cmd(server: Arc<Server>, cmd: Command) {
-    match cmd {
-        Command::Rejoin { players } if players.is_wildcard() => {
-            server.disconnect_all().await;
-            info!("Disconnected everyone");
-        }
-        Command::Rejoin { players } => {
-            server.disconnect_by_name(players.clone()).await;
-            info!("Disconnected {}", players.join(", "));
-        }
-        Command::Crash { players } if players.is_wildcard() => {
-            server
-                .broadcast(Packet::new(
-                    Uuid::nil(),
-                    Content::ChangeStage {
-                        stage: "baguette".to_owned(),
-                        id: "dufromage".to_owned(),
-                        scenario: 21,
-                        sub_scenario: 42,
-                    },
-                ))
-                .await;
+#[derive(Debug, Serialize)]
+#[cfg_attr(test, derive(serde::Deserialize, PartialEq))]
+pub struct TagTime {
+    pub id: Uuid,
+    pub name: String,
+    pub seconds: i64,
+    pub is_seeking: bool,
+}
+
+// Where the results of a command go, the console or the response of the admin api
+#[derive(Debug, Default)]
+pub struct Output {
+    captured: Option<Vec<String>>,
+    failed: bool,
+    result: Option<CommandResult>,
+}
+
+impl Output {
+    pub fn console() -> Self {
+        Self::default()
+    }
 
-            info!("Crashed everyone");
+    pub fn captured() -> Self {
+        Self {
+            captured: Some(vec![]),
+            failed: false,
+            result: None,
         }
-        Command::Crash { players } => {
-            server
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn text(&self) -> String {
+        self.captured
+            .as_ref()
+            .map(|lines| lines.join("\n"))
+            .unwrap_or_default()
+    }
+
+    pub fn take_result(&mut self) -> Option<CommandResult> {
+        self.result.take()
+    }
+
+    // Ignored by the console
+    fn set_result(&mut self, result: CommandResult) {
+        self.result = Some(result);
+    }
+
+    fn print(&mut self, message: String) {
+        match &mut self.captured {
+            Some(lines) => lines.push(message),
+            None => println!("{}", message),
+        }
+    }
+
+    fn error(&mut self, message: impl Display) {
+        self.failed = true;
+
+        match &mut self.captured {
+            Some(lines) => lines.push(message.to_string()),
+            None => println!("\n{}\n{}", "[Error]".red(), message),
+        }
+    }
+
+    fn invalid_command(&mut self, cmd: &str) {
+        let help = Command::Unknown { cmd: "".to_owned() }.help();
+
+        match &mut self.captured {
+            Some(_) => self.error(format!("Invalid command: {}\n\n{}", cmd, help)),
+            None => {
+                self.failed = true;
+                println!("\n{} {}\n\n{}", "Invalid command:".red(), cmd, help);
+            }
+        }
+    }
+}
+
+// Stopping the server with ctrl c is handled by main
+pub async fn listen(server: Arc<Server>) {
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        let line = match stdin.next_line().await {
+            Ok(Some(line)) => line,
+            // stdin is closed, e.g. in a container without a tty
+            Ok(None) => return,
+            Err(e) => {
+                error!("Failed to read stdin {}", e);
+                continue;
+            }
+        };
+
+        let mut output = Output::console();
+
+        match Command::parse(line.clone()) {
+            Ok(cmd) => {
+                if !cmd.is_unknown() {
+                    server.audit("console", line.trim()).await;
+                }
+
+                exec_cmd(server.clone(), cmd, &mut output).await
+            }
+            Err(message) => output.error(message),
+        };
+    }
+}
+
+// Ids of the players with those names, or of everyone connected for *
+async fn target_ids(server: &Server, players: &Vec<String>) -> Vec<Uuid> {
+    if players.is_wildcard() {
+        return server.connected_peers().await;
+    }
+
+    join_all(
+        players
+            .iter()
+            .map(|name| server.players.get_all_ids_by_name(name)),
+    )
+    .await
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+// Splits the players between the ones that fit in the stage according to server.stage_caps and
+// the names of the skipped ones. Players already in the stage don't take another slot
+async fn capped_targets(
+    server: &Server,
+    stage: &str,
+    ids: Vec<Uuid>,
+) -> (HashSet<Uuid>, Vec<String>) {
+    let cap = server
+        .settings
+        .read()
+        .await
+        .server
+        .stage_caps
+        .get(stage)
+        .copied();
+
+    let cap = match cap {
+        Some(cap) => usize::from(cap),
+        None => return (ids.into_iter().collect(), vec![]),
+    };
+
+    let mut free = cap.saturating_sub(server.stage_occupancy(stage).await);
+    let players = server.players.all_from_ids(ids).await;
+    let players = join_all(players.iter().map(|p| p.read())).await;
+
+    let mut allowed = HashSet::new();
+    let mut skipped = vec![];
+
+    for player in players.iter() {
+        if player.get_stage().as_deref() == Some(stage) {
+            allowed.insert(player.id);
+        } else if free > 0 {
+            free -= 1;
+            allowed.insert(player.id);
+        } else {
+            skipped.push(player.name.clone());
+        }
+    }
+
+    (allowed, skipped)
+}
+
+pub async fn exec_cmd(server: Arc<Server>, cmd: Command, output: &mut Output) {
+    match cmd {
+        Command::Rejoin {
+            players,
+            delay: Some(delay),
+        } if players.is_wildcard() => {
+            output.print(format!(
+                "Disconnecting everyone, {} apart",
+                format_duration(delay)
+            ));
+
+            tokio::spawn(async move {
+                server.disconnect_all_staggered(delay).await;
+                info!("Disconnected everyone");
+            });
+        }
+        Command::Rejoin { players, delay: _ } if players.is_wildcard() => {
+            server.disconnect_all().await;
+            output.print("Disconnected everyone".to_owned());
+        }
+        Command::Rejoin { players, delay: _ } => {
+            server.disconnect_by_name(players.clone()).await;
+            output.print(format!("Disconnected {}", players.join(", ")));
+        }
+        Command::Kick { players } => {
+            let ids = target_ids(&server, &players).await;
+
+            let cooldown = server.kick(ids).await;
+
+            output.print(format!(
+                "Kicked {}, they can reconnect in {}",
+                players.join(", "),
+                format_duration(cooldown)
+            ));
+        }
+        Command::Crash { players } if players.is_wildcard() => {
+            let delivered = server
+                .broadcast_counted(Packet::new(
+                    Uuid::nil(),
+                    Content::ChangeStage {
+                        stage: "baguette".to_owned(),
+                        id: "dufromage".to_owned(),
+                        scenario: 21,
+                        sub_scenario: 42,
+                    },
+                ))
+                .await;
+
+            output.print(format!(
+                "Crashed everyone, delivered to {} players",
+                delivered
+            ));
+        }
+        Command::Crash { players } => {
+            server
                 .broadcast_map(
                     Packet::new(
                         Uuid::nil(),
@@ -670,32 +1635,7 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
                 )
                 .await;
 
-            info!("Crashed {}", players.join(", "));
-        }
-        Command::Send {
-            stage,
-            id,
-            scenario,
-            players,
-        } if players.is_wildcard() => {
-            server
-                .broadcast(Packet::new(
-                    Uuid::nil(),
-                    Content::ChangeStage {
-                        id: id.clone(),
-                        stage: stage.to_str().to_owned(),
-                        scenario,
-                        sub_scenario: 0,
-                    },
-                ))
-                .await;
-
-            info!(
-                "Sent everyone to stage: {}, id: {}, scenario: {}",
-                stage.to_str(),
-                id,
-                scenario
-            );
+            output.print(format!("Crashed {}", players.join(", ")));
         }
         Command::Send {
             stage,
@@ -703,42 +1643,196 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
             scenario,
             players,
         } => {
-            server
-                .broadcast_map(
-                    Packet::new(
-                        Uuid::nil(),
-                        Content::ChangeStage {
-                            id: id.clone(),
-                            stage: stage.to_str().to_owned(),
-                            scenario,
-                            sub_scenario: 0,
-                        },
-                    ),
-                    |player, packet| {
-                        let players = players.clone();
+            let packet = Packet::new(
+                Uuid::nil(),
+                Content::ChangeStage {
+                    id: id.clone(),
+                    stage: stage.to_str().to_owned(),
+                    scenario,
+                    sub_scenario: 0,
+                },
+            );
+            let is_capped = server
+                .settings
+                .read()
+                .await
+                .server
+                .stage_caps
+                .contains_key(stage.to_str());
+
+            if players.is_wildcard() && !is_capped {
+                server.broadcast(packet).await;
+            } else {
+                let ids = target_ids(&server, &players).await;
+                let (allowed, skipped) = capped_targets(&server, stage.to_str(), ids).await;
+
+                if !skipped.is_empty() {
+                    output.error(format!(
+                        "{} is full, skipped {}",
+                        stage.to_str(),
+                        skipped.join(", ")
+                    ));
+                }
+
+                server
+                    .broadcast_map(packet, |player, packet| {
+                        let allowed = &allowed;
                         async move {
                             let player = player.read().await;
 
-                            if players.contains(&player.name) {
+                            if allowed.contains(&player.id) {
                                 Some(packet)
                             } else {
                                 None
                             }
                         }
-                    },
-                )
-                .await;
+                    })
+                    .await;
+            }
 
-            info!(
+            output.print(format!(
                 "Sent everyone to stage: {}, id: {}, scenario: {}",
                 stage.to_str(),
                 id,
                 scenario
-            );
+            ));
         }
         Command::SendAll { stage } => {
-            server
-                .broadcast(Packet::new(
+            let packet = Packet::new(
+                Uuid::nil(),
+                Content::ChangeStage {
+                    id: "".to_owned(),
+                    stage: stage.to_str().to_owned(),
+                    scenario: -1,
+                    sub_scenario: 0,
+                },
+            );
+            let is_capped = server
+                .settings
+                .read()
+                .await
+                .server
+                .stage_caps
+                .contains_key(stage.to_str());
+
+            let delivered = if is_capped {
+                let ids = server.connected_peers().await;
+                let (allowed, skipped) = capped_targets(&server, stage.to_str(), ids).await;
+
+                if !skipped.is_empty() {
+                    output.error(format!(
+                        "{} is full, skipped {}",
+                        stage.to_str(),
+                        skipped.join(", ")
+                    ));
+                }
+
+                let mut delivered = 0;
+
+                for id in allowed {
+                    if server.send_to(&id, packet.clone()).await.is_ok() {
+                        delivered += 1;
+                    }
+                }
+
+                delivered
+            } else {
+                server.broadcast_counted(packet).await
+            };
+
+            output.print(format!(
+                "Sent everyone to {}, delivered to {} players",
+                stage.to_str(),
+                delivered
+            ));
+        }
+        Command::SendStage { from, to, scenario } => {
+            let packet = Packet::new(
+                Uuid::nil(),
+                Content::ChangeStage {
+                    id: "".to_owned(),
+                    stage: to.to_str().to_owned(),
+                    scenario,
+                    sub_scenario: 0,
+                },
+            );
+            let is_capped = server
+                .settings
+                .read()
+                .await
+                .server
+                .stage_caps
+                .contains_key(to.to_str());
+
+            if is_capped {
+                let connected = server.connected_peers().await;
+                let players = server.players.all_from_ids(connected).await;
+                let players = join_all(players.iter().map(|p| p.read())).await;
+
+                let ids = players
+                    .iter()
+                    .filter(|player| player.get_stage().as_deref() == Some(from.to_str()))
+                    .map(|player| player.id)
+                    .collect();
+
+                drop(players);
+
+                let (allowed, skipped) = capped_targets(&server, to.to_str(), ids).await;
+
+                if !skipped.is_empty() {
+                    output.error(format!(
+                        "{} is full, skipped {}",
+                        to.to_str(),
+                        skipped.join(", ")
+                    ));
+                }
+
+                for id in allowed {
+                    let _ = server.send_to(&id, packet.clone()).await;
+                }
+            } else {
+                server.broadcast_to_stage(from.to_str(), packet).await;
+            }
+
+            output.print(format!(
+                "Sent the players in {} to stage: {}, scenario: {}",
+                from.to_str(),
+                to.to_str(),
+                scenario
+            ));
+        }
+        Command::Scatter { stages, seed } => {
+            let seed = seed.unwrap_or_else(rand::random);
+            let mut rng = StdRng::seed_from_u64(seed);
+
+            let all = Stage::ALL;
+            let stages = if stages.is_empty() {
+                &all[..]
+            } else {
+                &stages[..]
+            };
+
+            let connected = server.connected_peers().await;
+            let players = server.players.all_from_ids(connected).await;
+            let mut players = join_all(players.iter().map(|p| async {
+                let player = p.read().await;
+
+                (player.name.clone(), player.id)
+            }))
+            .await;
+
+            // Sorted so the same seed gives the same stages whatever the order of the HashMap
+            players.sort();
+
+            let mut assigned = vec![];
+
+            for (name, id) in players {
+                let stage = match stages.choose(&mut rng) {
+                    Some(stage) => stage,
+                    None => break,
+                };
+
+                let packet = Packet::new(
                     Uuid::nil(),
                     Content::ChangeStage {
                         id: "".to_owned(),
@@ -746,35 +1840,43 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
                         scenario: -1,
                         sub_scenario: 0,
                     },
-                ))
-                .await;
+                );
 
-            info!("Sent everyone to {}", stage.to_str());
+                if server.send_to(&id, packet).await.is_ok() {
+                    assigned.push(format!("{} -> {}", name, stage.name()));
+                }
+            }
+
+            output.print(format!(
+                "Scattered {} players with seed {}: {}",
+                assigned.len(),
+                seed,
+                assigned.join(", ")
+            ));
         }
         Command::Ban { players } => {
-            let mut settings = server.settings.write().await;
-            let peers = server.peers.read().await;
+            let ids = target_ids(&server, &players).await;
 
-            for name in players.clone() {
-                let id = server.players.get_id_by_name(name).await;
-
-                if id.is_none() {
-                    continue;
-                }
+            // The ip of the players the server knows is banned too
+            let peers = server.peers.read().await;
+            let bans: Vec<_> = ids
+                .iter()
+                .map(|id| (*id, peers.get(id).map(|peer| peer.ip)))
+                .collect();
+            drop(peers);
 
-                let id = id.unwrap();
+            let mut settings = server.settings.write().await;
 
-                let peer = peers.get(&id);
+            for (id, ip) in bans {
+                settings.ban_list.ban(id, ip);
+            }
 
-                if peer.is_none() {
-                    settings.ban_list.ban(id, None);
-                    settings.save().await;
-                    break;
-                }
+            settings.save().await;
+            drop(settings);
 
-                let peer = peer.unwrap();
-                settings.ban_list.ban(id, Some(peer.ip));
+            let peers = server.peers.read().await;
 
+            for peer in ids.iter().filter_map(|id| peers.get(id)) {
                 peer.send(Packet::new(
                     Uuid::nil(),
                     Content::ChangeStage {
@@ -785,61 +1887,745 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
                     },
                 ))
                 .await;
-                settings.save().await;
             }
 
-            info!("Banned {}", players.join(", "));
+            drop(peers);
+
+            output.print(format!("Banned {}", players.join(", ")));
+            output.set_result(CommandResult::Banned { ids });
+        }
+        Command::Unban { target } => {
+            let mut settings = server.settings.write().await;
+
+            let (removed, label) = match target {
+                BanTarget::Id(id) => (settings.ban_list.unban_id(&id), format!("id {}", id)),
+                BanTarget::Ip(ip) => (settings.ban_list.unban_ip(&ip), format!("ip {}", ip)),
+            };
+
+            if !removed {
+                output.error(format!("{} not found in the ban list", label));
+                return;
+            }
+
+            settings.save().await;
+
+            output.print(format!("Unbanned {}", label));
+        }
+        Command::BanIp { range } => {
+            let mut settings = server.settings.write().await;
+
+            if !settings.ban_list.ban_range(range) {
+                output.error(format!("{} is already in the ban list", range));
+                return;
+            }
+
+            settings.save().await;
+            drop(settings);
+
+            let peers = server.peers.read().await;
+            let mut disconnected = 0;
+
+            for peer in peers
+                .values()
+                .filter(|p| p.connected && range.contains(&p.ip))
+            {
+                peer.disconnect().await;
+                disconnected += 1;
+            }
+
+            output.print(format!(
+                "Banned {}, disconnected {} players",
+                range, disconnected
+            ));
+        }
+        Command::Allow { id } => {
+            let mut settings = server.settings.write().await;
+
+            if settings.allow_list.ids.contains(&id) {
+                output.error(format!("{} is already in the allow list", id));
+                return;
+            }
+
+            settings.allow_list.ids.push(id);
+            settings.save().await;
+
+            output.print(format!("Allowed {}", id));
+        }
+        Command::Unallow { id } => {
+            let mut settings = server.settings.write().await;
+
+            if !settings.allow_list.ids.contains(&id) {
+                output.error(format!("{} not found in the allow list", id));
+                return;
+            }
+
+            settings.allow_list.ids.retain(|allowed| *allowed != id);
+            settings.save().await;
+
+            output.print(format!("Removed {} from the allow list", id));
+        }
+        Command::Spectate { id } => {
+            let mut settings = server.settings.write().await;
+
+            if settings.spectators.ids.contains(&id) {
+                output.error(format!("{} is already a spectator", id));
+                return;
+            }
+
+            settings.spectators.ids.push(id);
+            settings.save().await;
+            drop(settings);
+
+            output.print(format!("{} will join as a spectator", id));
+
+            // Reconnecting removes them for the others
+            if let Some(peer) = server.peers.read().await.get(&id) {
+                peer.disconnect().await;
+            }
+        }
+        Command::Unspectate { id } => {
+            let mut settings = server.settings.write().await;
+
+            if !settings.spectators.ids.contains(&id) {
+                output.error(format!("{} isn't a spectator", id));
+                return;
+            }
+
+            settings.spectators.ids.retain(|spectator| *spectator != id);
+            settings.save().await;
+            drop(settings);
+
+            output.print(format!("{} will join as a player", id));
+
+            if let Some(peer) = server.peers.read().await.get(&id) {
+                peer.disconnect().await;
+            }
+        }
+        Command::Peers => {
+            let hide_ips = server.settings.read().await.server.hide_ips;
+            let mut peers: Vec<_> = server
+                .peers
+                .read()
+                .await
+                .values()
+                .map(|peer| (peer.id, peer.ip, peer.connected))
+                .collect();
+
+            peers.sort_by_key(|(id, _, _)| *id);
+
+            let names: HashMap<Uuid, String> = server
+                .players
+                .all_ids_and_names()
+                .await
+                .into_iter()
+                .collect();
+
+            let rows = peers
+                .into_iter()
+                .map(|(id, ip, connected)| {
+                    vec![
+                        id.to_string(),
+                        names.get(&id).cloned().unwrap_or_else(|| "-".to_owned()),
+                        if hide_ips {
+                            "hidden".to_owned()
+                        } else {
+                            ip.to_string()
+                        },
+                        if connected { "yes" } else { "no" }.to_owned(),
+                    ]
+                })
+                .collect();
+
+            output.print(format!(
+                "Peers: \n{}",
+                format_table(&["Id", "Name", "Ip", "Connected"], rows)
+            ));
+        }
+        Command::Drop { id } => {
+            let peers = server.peers.read().await;
+
+            match peers.get(&id) {
+                Some(peer) if peer.connected => {
+                    peer.disconnect().await;
+                    output.print(format!("Dropped {}", id));
+                }
+                Some(_) => output.error(format!("{} is already disconnected", id)),
+                None if server.players.get(&id).await.is_some() => {
+                    output.error(format!("{} has no connection", id))
+                }
+                None => output.error(format!("Couldn't find peer {}", id)),
+            }
+        }
+        Command::Scenario {
+            subcmd: ScenarioSubCmd::Merge { enabled },
+        } => {
+            let mut settings = server.settings.write().await;
+
+            settings.scenario.merge_enabled = enabled;
+            settings.save().await;
+
+            output.print(format!("Updated merge to {}", enabled));
+        }
+        Command::Scenario {
+            subcmd: ScenarioSubCmd::GetStage { stage },
+        } => {
+            let settings = server.settings.read().await;
+            let enabled = settings.scenario.merge_in(stage.to_str());
+            let source = if settings.scenario.stages.contains_key(stage.to_str()) {
+                "overridden"
+            } else {
+                "from scenario merge"
+            };
+
+            output.print(format!(
+                "Merge in {}: {} ({})",
+                stage.to_str(),
+                enabled,
+                source
+            ));
+        }
+        Command::Scenario {
+            subcmd: ScenarioSubCmd::SetStage { stage, enabled },
+        } => {
+            let mut settings = server.settings.write().await;
+
+            match enabled {
+                Some(enabled) => {
+                    settings
+                        .scenario
+                        .stages
+                        .insert(stage.to_str().to_owned(), enabled);
+                    output.print(format!(
+                        "Updated merge in {} to {}",
+                        stage.to_str(),
+                        enabled
+                    ));
+                }
+                None => {
+                    settings.scenario.stages.remove(stage.to_str());
+                    output.print(format!(
+                        "Merge in {} follows scenario merge again",
+                        stage.to_str()
+                    ));
+                }
+            }
+
+            settings.save().await;
+        }
+        Command::MaxPlayers { count } => {
+            let mut settings = server.settings.write().await;
+
+            settings.server.max_players = count;
+            settings.save().await;
+            drop(settings);
+
+            output.print(format!("Updated max players to {}", count));
+
+            let evicted = server.enforce_max_players(count as usize).await;
+
+            if evicted > 0 {
+                output.print(format!("Disconnected the {} most recent players", evicted));
+            }
+        }
+        Command::List => {
+            let connected = server.connected_peers().await;
+
+            let players = server.players.all_from_ids(connected).await;
+
+            let players = join_all(players.iter().map(|p| p.read())).await;
+
+            let rows = players
+                .iter()
+                .map(|player| {
+                    vec![
+                        match &player.captured {
+                            Some(model) => format!("{} as {}", player.name, model),
+                            None => player.name.clone(),
+                        },
+                        player.id.to_string(),
+                        player.shine_sync.len().to_string(),
+                        player
+                            .get_stage()
+                            .map_or_else(|| "-".to_owned(), |stage| Stage::display_raw(&stage)),
+                        if player.is_seeking { "yes" } else { "no" }.to_owned(),
+                    ]
+                })
+                .collect();
+
+            output.print(format!(
+                "Connected players: \n{}",
+                format_table(&["Name", "Id", "Moons", "Stage", "Seeker"], rows)
+            ));
+            output.set_result(CommandResult::Players {
+                players: players
+                    .iter()
+                    .map(|player| ListedPlayer {
+                        id: player.id,
+                        name: player.name.clone(),
+                        captured: player.captured.clone(),
+                        moons: player.shine_sync.len(),
+                        stage: player.get_stage(),
+                        is_seeking: player.is_seeking,
+                    })
+                    .collect(),
+            });
+        }
+        Command::Locations => {
+            let connected = server.connected_peers().await;
+
+            let players = server.players.all_from_ids(connected).await;
+
+            let players = join_all(players.iter().map(|p| p.read())).await;
+
+            let mut stages: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            let mut unknown = vec![];
+
+            for player in players.iter() {
+                match (player.get_stage(), player.scenario) {
+                    (Some(stage), scenario) => stages
+                        .entry(Stage::display_raw(&stage))
+                        .or_default()
+                        .push(format!(
+                            "{} -> {} (scenario {})",
+                            player.name,
+                            Stage::display_raw(&stage),
+                            scenario.map_or_else(|| "-".to_owned(), |s| s.to_string())
+                        )),
+                    (None, _) => unknown.push(player.name.clone()),
+                }
+            }
+
+            let mut groups: Vec<(String, Vec<String>)> = stages.into_iter().collect();
+
+            if !unknown.is_empty() {
+                groups.push(("Unknown".to_owned(), unknown));
+            }
+
+            let list = groups
+                .into_iter()
+                .map(|(stage, players)| {
+                    format!(
+                        "{}:\n{}",
+                        stage,
+                        players
+                            .iter()
+                            .map(|player| format!("- {}", player))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n");
+
+            output.print(format!("Players by stage: \n{}", list));
+        }
+        Command::Who => {
+            let hide_ips = server.settings.read().await.server.hide_ips;
+            let peers = server.peers.read().await;
+
+            let mut rows = vec![];
+
+            for peer in peers.values().filter(|p| p.connected) {
+                let player = match server.players.get(&peer.id).await {
+                    Some(player) => player,
+                    None => continue,
+                };
+
+                let player = player.read().await;
+
+                rows.push(vec![
+                    player.name.clone(),
+                    peer.id.to_string(),
+                    if hide_ips {
+                        "hidden".to_owned()
+                    } else {
+                        peer.ip.to_string()
+                    },
+                    format_duration(peer.connected_at.elapsed()),
+                    player
+                        .get_stage()
+                        .map_or_else(|| "-".to_owned(), |stage| Stage::display_raw(&stage)),
+                ]);
+            }
+
+            drop(peers);
+
+            output.print(format!(
+                "Connected players: \n{}",
+                format_table(&["Name", "Id", "Ip", "Connected for", "Stage"], rows)
+            ));
+        }
+        Command::Lookup { player } => {
+            let known = server
+                .players
+                .find_known(Uuid::from_str(&player).ok(), &player)
+                .await;
+
+            if known.is_empty() {
+                output.error(format!("Couldn't find player {}", player));
+                return;
+            }
+
+            let connected = server.connected_peers().await;
+
+            let list = known.iter().fold(String::from(""), |acc, (id, player)| {
+                let last_seen = if connected.contains(id) {
+                    "online".to_owned()
+                } else {
+                    format!(
+                        "last seen {} ago",
+                        format_duration(
+                            (Utc::now() - player.last_seen).to_std().unwrap_or_default()
+                        )
+                    )
+                };
+
+                format!(
+                    "{}{}- [{}] -> {} ({})",
+                    acc,
+                    if acc.is_empty() { "" } else { "\n" },
+                    player.name,
+                    id,
+                    last_seen
+                )
+            });
+
+            output.print(format!("Known players: \n{}", list));
+        }
+        Command::Rename { id, name } => {
+            if let Err(message) = server.players.rename(&id, name.clone()).await {
+                output.error(message);
+                return;
+            }
+
+            let max_player = server.settings.read().await.server.max_players as u16;
+
+            server
+                .broadcast(Packet::new(
+                    id,
+                    Content::Connect {
+                        type_: ConnectionType::Reconnect,
+                        max_player,
+                        client: name.clone(),
+                    },
+                ))
+                .await;
+
+            server.persist_players().await;
+
+            output.print(format!("Renamed {} to {}", id, name));
+        }
+        Command::Stats => {
+            let rejections = server.metrics.rejections();
+
+            let list = rejections
+                .iter()
+                .fold(String::from(""), |acc, (reason, count)| {
+                    format!(
+                        "{}{}- {}: {}",
+                        acc,
+                        if acc.is_empty() { "" } else { "\n" },
+                        reason.to_str(),
+                        count
+                    )
+                });
+
+            output.print(format!("Rejected connections: \n{}", list));
+        }
+        Command::Announce { message } => {
+            let delivered = server
+                .broadcast_counted(Packet::new(Uuid::nil(), Content::ChatMessage { message }))
+                .await;
+
+            output.print(format!("Announced, delivered to {} players", delivered));
+            output.set_result(CommandResult::Announced { delivered });
+        }
+        Command::Whisper { player, message } => {
+            let packet = Packet::new(Uuid::nil(), Content::ChatMessage { message });
+
+            let mut delivered = false;
+
+            for id in server.players.get_all_ids_by_name(&player).await {
+                delivered |= server.send_to(&id, packet.clone()).await.is_ok();
+            }
+
+            if delivered {
+                output.print(format!("Whispered to {}", player))
+            } else {
+                output.error(format!("Couldn't find player {}", player))
+            }
+        }
+        Command::Pause { paused } => {
+            server.set_broadcasting_paused(paused).await;
+
+            if paused {
+                output.print("Paused broadcasting".to_owned());
+            } else {
+                output.print("Resumed broadcasting".to_owned());
+            }
+        }
+        Command::Resync { player: None } => {
+            server.resync(None).await;
+
+            output.print("Resynced every player".to_owned());
+        }
+        Command::Resync {
+            player: Some(player),
+        } => {
+            let ids = server.players.get_all_ids_by_name(&player).await;
+
+            if ids.is_empty() {
+                output.error(format!("Couldn't find player {}", player));
+                return;
+            }
+
+            for id in ids {
+                server.resync(Some(id)).await;
+            }
+
+            output.print(format!("Resynced {}", player));
+        }
+        Command::Freeze { player: username } => {
+            let ids = server.players.get_all_ids_by_name(&username).await;
+            let players = server.players.all_from_ids(ids).await;
+
+            if players.is_empty() {
+                output.error(format!("Couldn't find player {}", username));
+                return;
+            }
+
+            for player in players {
+                let mut player = player.write().await;
+
+                match player.last_position.clone() {
+                    Some(position) => {
+                        player.frozen_position = Some(position);
+                        output.print(format!("Froze {}", player.name));
+                    }
+                    None => output.error(format!("{} didn't send a position yet", player.name)),
+                }
+            }
+        }
+        Command::Unfreeze { player: username } => {
+            let ids = server.players.get_all_ids_by_name(&username).await;
+            let players = server.players.all_from_ids(ids).await;
+
+            if players.is_empty() {
+                output.error(format!("Couldn't find player {}", username));
+                return;
+            }
+
+            for player in players {
+                let mut player = player.write().await;
+
+                if player.frozen_position.take().is_some() {
+                    output.print(format!("Unfroze {}", player.name));
+                } else {
+                    output.error(format!("{} isn't frozen", player.name));
+                }
+            }
+        }
+        Command::Teleport { from, to } => {
+            let destination = match server.players.get_id_by_name(to.clone()).await {
+                Some(id) => server.players.get(&id).await,
+                None => None,
+            };
+
+            let destination = match destination {
+                Some(player) => player,
+                None => {
+                    output.error(format!("Couldn't find player {}", to));
+                    return;
+                }
+            };
+
+            let destination = destination.read().await;
+            let (stage, scenario, position) = match (destination.get_stage(), destination.scenario)
+            {
+                (Some(stage), Some(scenario)) => (stage, scenario, destination.last_position()),
+                _ => {
+                    output.error(format!("{} isn't in a stage yet", destination.name));
+                    return;
+                }
+            };
+            drop(destination);
+
+            let ids = server.players.get_all_ids_by_name(&from).await;
+
+            if ids.is_empty() {
+                output.error(format!("Couldn't find player {}", from));
+                return;
+            }
+
+            for player in server.players.all_from_ids(ids).await {
+                let player = player.read().await;
+
+                if player.last_game_packet.is_none() {
+                    output.error(format!("{} isn't in a stage yet", player.name));
+                    continue;
+                }
+
+                let packet = Packet::new(
+                    Uuid::nil(),
+                    Content::ChangeStage {
+                        id: "".to_owned(),
+                        stage: stage.clone(),
+                        scenario: Content::clamp_scenario(scenario),
+                        sub_scenario: 0,
+                    },
+                );
+
+                if server.send_to(&player.id, packet).await.is_ok() {
+                    output.print(format!(
+                        "Sent {} to {} in {}{}",
+                        player.name,
+                        to,
+                        Stage::display_raw(&stage),
+                        position.map_or_else(String::new, |p| format!(
+                            ", they're at {:.0} {:.0} {:.0}",
+                            p.x, p.y, p.z
+                        ))
+                    ));
+                }
+            }
+        }
+        Command::LoadSettings => {
+            let applied = match Settings::load().await {
+                Ok(updated) => server.apply_settings(updated).await,
+                Err(message) => Err(message),
+            };
+
+            if let Err(message) = applied {
+                output.error(format!("Settings not loaded: {}", message));
+            }
+        }
+        Command::Restart => {
+            let mut updated = match Settings::read().await {
+                Ok(updated) => updated,
+                Err(message) => {
+                    output.error(message);
+                    return;
+                }
+            };
+            updated.sanitize();
+
+            let changed = server.settings.read().await.diff(&updated);
+            let max_players = updated.server.max_players;
+
+            if let Err(message) = server.apply_settings(updated).await {
+                output.error(format!("Settings not loaded: {}", message));
+                return;
+            }
+
+            if changed.is_empty() {
+                output.print("No setting changed".to_owned());
+            } else {
+                output.print(format!("Changed settings: {}", changed.join(", ")));
+            }
+
+            let evicted = server
+                .enforce_max_players(usize::try_from(max_players).unwrap_or_default())
+                .await;
+
+            if evicted > 0 {
+                output.print(format!("Disconnected the {} most recent players", evicted));
+            }
+
+            if let Err(e) = server.listen().await {
+                output.error(format!("Couldn't listen on the new addresses: {}", e));
+            }
         }
-        Command::Scenario { subcmd, value } => match subcmd.as_str() {
-            "merge" => {
-                let mut settings = server.settings.write().await;
-                if value.as_str() == "true" {
-                    settings.scenario.merge_enabled = true;
-                    settings.save().await;
-                    info!("Updated merge to {}", true);
-                } else if value.as_str() == "false" {
-                    settings.scenario.merge_enabled = false;
-                    settings.save().await;
-                    info!("Updated merge to {}", false);
-                } else {
-                    println!("{}", Command::default_from_str("scenario").help())
+        Command::Save { slot } => {
+            let path = match Settings::profile_path(&slot) {
+                Ok(path) => path,
+                Err(message) => {
+                    output.error(message);
+                    return;
                 }
+            };
+
+            match server.settings.read().await.save_as(&path).await {
+                Ok(_) => output.print(format!("Saved the settings to the profile {}", slot)),
+                Err(message) => output.error(message),
             }
-            _ => println!("{}", Command::default_from_str("scenario").help()),
-        },
-        Command::MaxPlayers { count } => {
-            let mut settings = server.settings.write().await;
+        }
+        Command::Load { slot } => {
+            let profile = match Settings::profile_path(&slot) {
+                Ok(path) if !path.exists() => Err(format!(
+                    "Profile {} not found, use profiles to list them",
+                    slot
+                )),
+                Ok(path) => Settings::load_from(&path).await,
+                Err(message) => Err(message),
+            };
 
-            settings.server.max_players = count as i16;
-            settings.save().await;
+            let profile = match profile {
+                Ok(profile) => profile,
+                Err(message) => {
+                    output.error(message);
+                    return;
+                }
+            };
 
-            info!("Updated max players to {}", count);
-        }
-        Command::List => {
-            let connected = server.connected_peers().await;
+            let settings = server.settings.read().await;
+            let updated = settings.merge_profile(profile);
+            let changed = settings.diff(&updated);
+            drop(settings);
 
-            let players = server.players.all_from_ids(connected).await;
+            let max_players = updated.server.max_players;
 
-            let players = join_all(players.iter().map(|p| p.read())).await;
+            if let Err(message) = server.apply_settings(updated).await {
+                output.error(format!("Profile {} not loaded: {}", slot, message));
+                return;
+            }
+            // So loadsettings and restart don't bring the previous settings back
+            server.settings.read().await.save().await;
 
-            let list = players.iter().fold(String::from(""), |acc, player| {
-                format!(
-                    "{}{}- [{}] -> {}",
-                    acc,
-                    if acc.is_empty() { "" } else { "\n" },
-                    player.name,
-                    player.id
-                )
-            });
+            if changed.is_empty() {
+                output.print(format!("Loaded the profile {}, no setting changed", slot));
+            } else {
+                output.print(format!(
+                    "Loaded the profile {}, changed settings: {}",
+                    slot,
+                    changed.join(", ")
+                ));
+            }
+
+            let evicted = server
+                .enforce_max_players(usize::try_from(max_players).unwrap_or_default())
+                .await;
 
-            println!("Connected players: \n{}", list);
+            if evicted > 0 {
+                output.print(format!("Disconnected the {} most recent players", evicted));
+            }
         }
-        Command::LoadSettings => {
-            let updated = Settings::load().await;
+        Command::Profiles => {
+            let profiles = Settings::profiles().await;
+
+            if profiles.is_empty() {
+                output.print("No saved profile, use save <profile> to create one".to_owned());
+            } else {
+                output.print(profiles.join("\n"));
+            }
+        }
+        Command::Get { path } => {
+            let settings = server.settings.read().await;
 
+            match settings.get_path(&path) {
+                Ok(value) => output.print(format!("{} = {}", path, value)),
+                Err(message) => output.error(message),
+            }
+        }
+        Command::Set { path, value } => {
             let mut settings = server.settings.write().await;
 
-            *settings = updated;
+            match settings.set_path(&path, &value) {
+                Ok(_) => {
+                    settings.save().await;
+                    output.print(format!("Updated {} to {}", path, value));
+                }
+                Err(message) => output.error(message),
+            }
         }
         Command::Tag {
             subcmd:
@@ -861,10 +2647,65 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
 
             if username.as_str() == "*" {
                 server.broadcast(packet).await;
-            } else if let Some(id) = server.players.get_id_by_name(username.clone()).await {
+                output.print("Updated time of everyone".to_owned());
+            } else {
+                let ids = server.players.get_all_ids_by_name(&username).await;
+
+                if ids.is_empty() {
+                    output.error(format!("Couldn't find player {}", username));
+                }
+
+                for id in ids {
+                    match server.send_to(&id, packet.clone()).await {
+                        Ok(_) => output.print(format!("Updated time of {}", username)),
+                        Err(_) => output.error(format!("Couldn't find player {}", username)),
+                    }
+                }
+            }
+        }
+        Command::Tag {
+            subcmd: TagSubCmd::AddTime { username, seconds },
+        } => {
+            let ids = server.players.get_all_ids_by_name(&username).await;
+
+            if ids.is_empty() {
+                output.error(format!("Couldn't find player {}", username));
+                return;
+            }
+
+            for id in ids {
+                let player = match server.players.get(&id).await {
+                    Some(player) => player,
+                    None => continue,
+                };
+
+                let mut player = player.write().await;
+
+                player.time = (player.time + chrono::Duration::seconds(seconds))
+                    .max(chrono::Duration::zero());
+
+                let time = player.time;
+
+                drop(player);
+
+                let packet = Packet::new(
+                    Uuid::nil(),
+                    Content::Tag {
+                        update_type: TagUpdate::Time.as_byte(),
+                        is_it: false,
+                        seconds: u16::try_from(time.num_seconds() % 60).unwrap_or(0),
+                        minutes: u16::try_from(time.num_minutes()).unwrap_or(u16::MAX),
+                    },
+                );
+
                 match server.send_to(&id, packet).await {
-                    Ok(_) => info!("Updated time of {}", username),
-                    Err(_) => info!("Couldn't find player {}", username),
+                    Ok(_) => output.print(format!(
+                        "Time of {} is now {}:{:02}",
+                        username,
+                        time.num_minutes(),
+                        time.num_seconds() % 60
+                    )),
+                    Err(_) => output.error(format!("Couldn't find player {}", username)),
                 }
             }
         }
@@ -883,10 +2724,34 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
 
             if username.as_str() == "*" {
                 server.broadcast(packet).await;
-            } else if let Some(id) = server.players.get_id_by_name(username.clone()).await {
-                match server.send_to(&id, packet).await {
-                    Ok(_) => info!("Updated time of {}", username),
-                    Err(_) => info!("Couldn't find player {}", username),
+                output.print(format!(
+                    "Set everyone as {}",
+                    if state == TagState::Seeker {
+                        "seeker"
+                    } else {
+                        "hider"
+                    }
+                ));
+            } else {
+                let ids = server.players.get_all_ids_by_name(&username).await;
+
+                if ids.is_empty() {
+                    output.error(format!("Couldn't find player {}", username));
+                }
+
+                for id in ids {
+                    match server.send_to(&id, packet.clone()).await {
+                        Ok(_) => output.print(format!(
+                            "Set {} as {}",
+                            username,
+                            if state == TagState::Seeker {
+                                "seeker"
+                            } else {
+                                "hider"
+                            }
+                        )),
+                        Err(_) => output.error(format!("Couldn't find player {}", username)),
+                    }
                 }
             }
         }
@@ -897,63 +2762,190 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
                     seekers: will_seek,
                 },
         } => {
-            tokio::spawn(async move {
-                sleep(Duration::from_secs(u64::from(time))).await;
+            let handle = tokio::spawn({
+                let server = server.clone();
 
-                let players = server.players.all_ids_and_names().await;
-
-                let [seekers, hiders] = players.into_iter().fold(
-                    [vec![], vec![]],
-                    |[mut seekers, mut hiders], (id, username)| {
-                        if will_seek.contains(&username) {
-                            seekers.push(id);
-                        } else {
-                            hiders.push(id);
-                        }
+                async move {
+                    let players = server.players.all_ids_and_names().await;
+                    let participants = players.iter().map(|(id, _)| *id).collect::<Vec<_>>();
 
-                        [seekers, hiders]
-                    },
-                );
+                    server.tag_countdown(&participants, u32::from(time)).await;
 
-                let peers = server.peers.read().await;
+                    let [seekers, hiders] = players.into_iter().fold(
+                        [vec![], vec![]],
+                        |[mut seekers, mut hiders], (id, username)| {
+                            if will_seek.contains(&username) {
+                                seekers.push(id);
+                            } else {
+                                hiders.push(id);
+                            }
 
-                for id in seekers {
-                    if let Some(peer) = peers.get(&id) {
-                        peer.send(Packet::new(
-                            Uuid::nil(),
-                            Content::Tag {
-                                update_type: TagUpdate::State.as_byte(),
-                                is_it: true,
-                                seconds: 0,
-                                minutes: 0,
-                            },
-                        ))
-                        .await
+                            [seekers, hiders]
+                        },
+                    );
+
+                    let peers = server.peers.read().await;
+
+                    for id in seekers {
+                        if let Some(peer) = peers.get(&id) {
+                            peer.send(Packet::new(
+                                Uuid::nil(),
+                                Content::Tag {
+                                    update_type: TagUpdate::State.as_byte(),
+                                    is_it: true,
+                                    seconds: 0,
+                                    minutes: 0,
+                                },
+                            ))
+                            .await
+                        }
                     }
-                }
 
-                for id in hiders {
-                    if let Some(peer) = peers.get(&id) {
-                        peer.send(Packet::new(
-                            Uuid::nil(),
-                            Content::Tag {
-                                update_type: TagUpdate::State.as_byte(),
-                                is_it: false,
-                                seconds: 0,
-                                minutes: 0,
-                            },
-                        ))
-                        .await
+                    for id in hiders {
+                        if let Some(peer) = peers.get(&id) {
+                            peer.send(Packet::new(
+                                Uuid::nil(),
+                                Content::Tag {
+                                    update_type: TagUpdate::State.as_byte(),
+                                    is_it: false,
+                                    seconds: 0,
+                                    minutes: 0,
+                                },
+                            ))
+                            .await
+                        }
                     }
                 }
             });
+
+            if let Some(previous) = server.tag_start.lock().await.replace(handle) {
+                previous.abort();
+            }
+        }
+        Command::Tag {
+            subcmd: TagSubCmd::Stop,
+        } => {
+            let pending = server
+                .tag_start
+                .lock()
+                .await
+                .take()
+                .filter(|handle| !handle.is_finished());
+
+            if let Some(handle) = &pending {
+                handle.abort();
+            }
+
+            server.tag_countdown.store(0, Ordering::Relaxed);
+            server.tag_countdown_paused.store(false, Ordering::Relaxed);
+            server.restore_tag_times().await;
+
+            let reset = server
+                .broadcast_counted(Packet::new(
+                    Uuid::nil(),
+                    Content::Tag {
+                        update_type: TagUpdate::State.as_byte(),
+                        is_it: false,
+                        seconds: 0,
+                        minutes: 0,
+                    },
+                ))
+                .await;
+
+            output.print(format!(
+                "Stopped the round{}, set {} players to hider",
+                if pending.is_some() {
+                    " and cancelled the pending start"
+                } else {
+                    ""
+                },
+                reset
+            ));
+        }
+        Command::Tag {
+            subcmd: subcmd @ (TagSubCmd::Pause | TagSubCmd::Resume),
+        } => {
+            let is_running = server
+                .tag_start
+                .lock()
+                .await
+                .as_ref()
+                .is_some_and(|handle| !handle.is_finished());
+
+            if !is_running {
+                output.error("No tag countdown is running, use tag start first");
+                return;
+            }
+
+            let pause = matches!(subcmd, TagSubCmd::Pause);
+            server.tag_countdown_paused.store(pause, Ordering::Relaxed);
+
+            let left = server.tag_countdown.load(Ordering::Relaxed);
+
+            if pause {
+                output.print(format!(
+                    "Paused the countdown at {}:{:02}",
+                    left / 60,
+                    left % 60
+                ));
+            } else {
+                output.print(format!(
+                    "Resumed the countdown at {}:{:02}",
+                    left / 60,
+                    left % 60
+                ));
+            }
+        }
+        Command::Tag {
+            subcmd: TagSubCmd::Leaderboard,
+        } => {
+            let connected = server.connected_peers().await;
+
+            let players = server.players.all_from_ids(connected).await;
+
+            let mut players = join_all(players.iter().map(|p| p.read())).await;
+
+            players.sort_by_key(|player| std::cmp::Reverse(player.time));
+
+            let rows = players
+                .iter()
+                .enumerate()
+                .map(|(i, player)| {
+                    vec![
+                        (i + 1).to_string(),
+                        player.name.clone(),
+                        format!(
+                            "{}:{:02}",
+                            player.time.num_minutes(),
+                            player.time.num_seconds() % 60
+                        ),
+                        if player.is_seeking { "seeker" } else { "hider" }.to_owned(),
+                    ]
+                })
+                .collect();
+
+            output.print(format!(
+                "Leaderboard: \n{}",
+                format_table(&["#", "Name", "Time", "Role"], rows)
+            ));
+            output.set_result(CommandResult::Leaderboard {
+                players: players
+                    .iter()
+                    .map(|player| TagTime {
+                        id: player.id,
+                        name: player.name.clone(),
+                        seconds: player.time.num_seconds(),
+                        is_seeking: player.is_seeking,
+                    })
+                    .collect(),
+            });
         }
         Command::Flip {
             subcmd: FlipSubCmd::List,
         } => {
             let settings = server.settings.read().await;
 
-            info!(
+            output.print(format!(
                 "User ids: {}",
                 settings
                     .flip
@@ -962,7 +2954,7 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
                     .map(std::string::ToString::to_string)
                     .collect::<Vec<String>>()
                     .join(", ")
-            );
+            ));
         }
         Command::Flip {
             subcmd: FlipSubCmd::Add { user_id },
@@ -976,9 +2968,9 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
 
                 settings.save().await;
 
-                info!("Added {} to flip list", user_id);
+                output.print(format!("Added {} to flip list", user_id));
             } else {
-                info!("Player {} was already in the list", user_id);
+                output.print(format!("Player {} was already in the list", user_id));
             }
         }
         Command::Flip {
@@ -993,9 +2985,9 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
 
                 settings.save().await;
 
-                info!("Removed {} from the flip list", user_id);
+                output.print(format!("Removed {} from the flip list", user_id));
             } else {
-                info!("Player {} wasn't in the list", user_id);
+                output.print(format!("Player {} wasn't in the list", user_id));
             }
         }
         Command::Flip {
@@ -1006,7 +2998,10 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
 
             settings.save().await;
 
-            info!("{} flip", if enabled { "Enabled" } else { "Disabled" });
+            output.print(format!(
+                "{} flip",
+                if enabled { "Enabled" } else { "Disabled" }
+            ));
         }
         Command::Flip {
             subcmd: FlipSubCmd::Pov { pov },
@@ -1016,72 +3011,390 @@ async fn exec_cmd(server: Arc<Server>, cmd: Command) {
 
             settings.save().await;
 
-            info!("Set pov to {}", pov.to_str());
+            output.print(format!("Set pov to {}", pov.to_str()));
+        }
+        Command::Costume {
+            subcmd: CostumeSubCmd::Reset { players },
+        } => {
+            let ids = target_ids(&server, &players).await;
+
+            for id in ids {
+                if let Err(e) = server.reset_costume(id).await {
+                    output.error(format!("{}: {}", id, e));
+                }
+            }
+
+            output.print(format!("Reset the costume of {}", players.join(", ")));
+        }
+        Command::Costume {
+            subcmd: CostumeSubCmd::Set { player, body, cap },
+        } => {
+            for name in [&body, &cap] {
+                if name.len() > COSTUME_SIZE {
+                    output.print(format!(
+                        "{} is longer than {} bytes, it will be truncated",
+                        name, COSTUME_SIZE
+                    ));
+                }
+            }
+
+            let ids = target_ids(&server, &vec![player.clone()]).await;
+
+            if ids.is_empty() {
+                output.error(format!("Couldn't find player {}", player));
+                return;
+            }
+
+            for id in ids {
+                if let Err(e) = server.force_costume(id, body.clone(), cap.clone()).await {
+                    output.error(format!("{}: {}", id, e));
+                }
+            }
+
+            output.print(format!("Set the costume of {} to {} {}", player, body, cap));
+        }
+        Command::Hide {
+            subcmd: HideSubCmd::Add { target, observer },
+        } => {
+            let target_id = server.players.get_id_by_name(target.clone()).await;
+            let observer_id = server.players.get_id_by_name(observer.clone()).await;
+
+            match (target_id, observer_id) {
+                (Some(target_id), Some(observer_id)) => {
+                    server.hidden.write().await.insert((target_id, observer_id));
+                    output.print(format!("{} is now hidden from {}", target, observer));
+                }
+                _ => output.error(format!("Couldn't find player {} or {}", target, observer)),
+            }
+        }
+        Command::Hide {
+            subcmd: HideSubCmd::Remove { target, observer },
+        } => {
+            let target_id = server.players.get_id_by_name(target.clone()).await;
+            let observer_id = server.players.get_id_by_name(observer.clone()).await;
+
+            match (target_id, observer_id) {
+                (Some(target_id), Some(observer_id))
+                    if server
+                        .hidden
+                        .write()
+                        .await
+                        .remove(&(target_id, observer_id)) =>
+                {
+                    output.print(format!(
+                        "{} is not hidden from {} anymore",
+                        target, observer
+                    ))
+                }
+                _ => output.print(format!("{} isn't hidden from {}", target, observer)),
+            }
+        }
+        Command::Hide {
+            subcmd: HideSubCmd::List,
+        } => {
+            let hidden = server.hidden.read().await.clone();
+            let names = server.players.all_ids_and_names().await;
+            let name = |id: &Uuid| {
+                names
+                    .iter()
+                    .find(|(player_id, _)| player_id == id)
+                    .map_or_else(|| id.to_string(), |(_, name)| name.clone())
+            };
+
+            let list = hidden
+                .iter()
+                .fold(String::from(""), |acc, (target, observer)| {
+                    format!(
+                        "{}{}- {} is hidden from {}",
+                        acc,
+                        if acc.is_empty() { "" } else { "\n" },
+                        name(target),
+                        name(observer)
+                    )
+                });
+
+            output.print(format!("Hide rules: \n{}", list));
+        }
+        Command::Hide {
+            subcmd: HideSubCmd::Clear,
+        } => {
+            server.hidden.write().await.clear();
+
+            output.print("Cleared all the hide rules".to_owned());
         }
         Command::Shine {
             subcmd: ShineSubCmd::List,
         } => {
             let bag = server.shine_bag.read().await;
 
-            let string = bag
-                .iter()
-                .fold("".to_owned(), |acc, id| format!("{}{}{}", acc, id, ", "));
+            let string = bag.iter().fold("".to_owned(), |acc, (id, is_grand)| {
+                format!(
+                    "{}{}{}{}",
+                    acc,
+                    id,
+                    if *is_grand { " (grand)" } else { "" },
+                    ", "
+                )
+            });
+
+            output.print(string);
+        }
+        Command::Shine {
+            subcmd: ShineSubCmd::Clear { confirmed: false },
+        } => output.error(format!(
+            "This will delete all the collected moons, use {} to do it",
+            "shine clear confirm".cyan()
+        )),
+        Command::Shine {
+            subcmd: ShineSubCmd::Clear { confirmed: true },
+        } => {
+            let count = server.clear_shines().await;
 
-            info!("{}", string);
+            output.print(format!("Cleared {} moons", count));
         }
         Command::Shine {
-            subcmd: ShineSubCmd::Clear,
+            subcmd: ShineSubCmd::Push { id, is_grand },
         } => {
-            let mut bag = server.shine_bag.write().await;
+            let count = server.push_shine(id, is_grand).await;
 
-            bag.clear();
+            output.print(format!(
+                "Pushed {}moon {} to {} players",
+                if is_grand { "grand " } else { "" },
+                id,
+                count
+            ));
+        }
+        Command::Shine {
+            subcmd: ShineSubCmd::Profile { name },
+        } => match server.switch_shine_profile(&name).await {
+            Ok(_) => output.print(format!("Switched to the moon profile {}", name)),
+            Err(e) => output.error(e),
+        },
+        Command::Shine {
+            subcmd: ShineSubCmd::Count,
+        } => {
+            let count = server.shine_bag.read().await.len();
 
-            info!("Cleared all the moons");
+            output.print(format!("{} moons collected", count));
         }
+        Command::Shine {
+            subcmd: ShineSubCmd::Dump { path },
+        } => match server.dump_shines(&path).await {
+            Ok(count) => output.print(format!("Saved {} moons to {}", count, path)),
+            Err(e) => output.error(format!("Couldn't save the moons to {}: {}", path, e)),
+        },
         Command::Shine {
             subcmd: ShineSubCmd::Sync,
         } => {
             server.sync_shine_bag().await;
 
-            info!("Synced moons");
+            output.print("Synced moons".to_owned());
         }
         Command::Shine {
             subcmd: ShineSubCmd::Send { id, players },
         } => {
-            let packet = Packet::new(Uuid::nil(), Content::Shine { id });
+            let packet = Packet::new(
+                Uuid::nil(),
+                Content::Shine {
+                    id,
+                    is_grand: false,
+                },
+            );
 
             if players.is_wildcard() {
                 server.broadcast(packet).await
             } else {
+                let ids = target_ids(&server, &players).await;
                 let peers = server.peers.read().await;
 
-                for username in players.clone() {
-                    let id = server.players.get_id_by_name(username).await;
-
-                    if id.is_none() {
-                        continue;
-                    }
-
-                    let id = id.unwrap();
-
+                for id in ids {
                     if let Some(peer) = peers.get(&id) {
                         peer.send(packet.clone()).await;
                     }
                 }
             }
 
-            info!("Sent moon {} to {}", id, players.join(", "));
+            output.print(format!("Sent moon {} to {}", id, players.join(", ")));
         }
         Command::Stop => {
+            server.shutdown().await;
             exit(0);
         }
         Command::Unknown { cmd } => {
-            println!(
-                "\n{} {}\n\n{}",
-                "Invalid command:".red(),
-                cmd,
-                Command::Unknown { cmd: "".to_owned() }.help()
+            output.invalid_command(&cmd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{start, wait_for, Client};
+
+    async fn run(server: &Arc<Server>, cmd: &str) -> Output {
+        let mut output = Output::captured();
+
+        exec_cmd(
+            server.clone(),
+            Command::parse(cmd.to_owned()).unwrap(),
+            &mut output,
+        )
+        .await;
+
+        output
+    }
+
+    #[test]
+    fn stages_are_home_stages() {
+        for stage in Stage::ALL {
+            assert!(
+                stage.to_str().ends_with("WorldHomeStage"),
+                "{}",
+                stage.to_str()
+            );
+        }
+    }
+
+    #[test]
+    fn stages_round_trip() {
+        for stage in Stage::ALL {
+            assert_eq!(
+                Stage::from_raw(stage.to_str()).map(|read| read.to_str()),
+                Some(stage.to_str())
+            );
+            assert_eq!(
+                Stage::from_str(stage.name()).map(|read| read.to_str()),
+                Ok(stage.to_str())
+            );
+        }
+    }
+
+    #[test]
+    fn shine_push_parses_grand() {
+        for (cmd, grand) in [("shine push 12", false), ("shine push 12 grand", true)] {
+            assert!(matches!(
+                Command::parse(cmd.to_owned()),
+                Ok(Command::Shine {
+                    subcmd: ShineSubCmd::Push { id: 12, is_grand },
+                }) if is_grand == grand
+            ));
+        }
+
+        assert!(Command::parse("shine push 12 big".to_owned()).is_err());
+    }
+
+    #[tokio::test]
+    async fn scenario_merge_parses_the_value() {
+        let (server, _) = start(Settings::default()).await;
+
+        for enabled in [true, false] {
+            let output = run(&server, &format!("scenario merge {}", enabled)).await;
+
+            assert!(!output.failed());
+            assert_eq!(server.settings.read().await.scenario.merge_enabled, enabled);
+            assert_eq!(
+                server
+                    .settings
+                    .read()
+                    .await
+                    .scenario
+                    .merge_in("CapWorldHomeStage"),
+                enabled
+            );
+        }
+
+        assert!(Command::parse("scenario merge yes".to_owned()).is_err());
+    }
+
+    #[tokio::test]
+    async fn shine_push_sends_the_grand_flag() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut client = Client::connect(&server, addr, "alice").await;
+
+        for is_grand in [false, true] {
+            run(
+                &server,
+                &format!("shine push 7{}", if is_grand { " grand" } else { "" }),
+            )
+            .await;
+
+            let shine = client
+                .recv_until(|p| matches!(p.content, Content::Shine { .. }))
+                .await
+                .map(|p| p.content);
+
+            assert!(
+                matches!(shine, Some(Content::Shine { id: 7, is_grand: sent }) if sent == is_grand),
+                "{:?}",
+                shine
             );
         }
+
+        assert_eq!(
+            server.shine_bag.read().await.iter().collect::<Vec<_>>(),
+            vec![&(7, true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn banip_bans_the_whole_range() {
+        let (server, _) = start(Settings::default()).await;
+
+        assert!(!run(&server, "banip 192.168.1.7/24").await.failed());
+
+        let settings = server.settings.read().await;
+
+        assert!(settings
+            .ban_list
+            .is_ip_ban(&"192.168.1.200".parse().unwrap()));
+        assert!(!settings.ban_list.is_ip_ban(&"192.168.2.1".parse().unwrap()));
+        assert_eq!(
+            settings.ban_list.ips,
+            vec!["192.168.1.0/24".parse::<IpNet>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn maxplayers_disconnects_the_most_recent_players() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let mut bob = Client::connect(&server, addr, "bob").await;
+        let mut carol = Client::connect(&server, addr, "carol").await;
+
+        assert!(Command::parse("maxplayers 0".to_owned()).is_err());
+        assert!(Command::parse("maxplayers 40000".to_owned()).is_err());
+
+        assert!(!run(&server, "maxplayers 1").await.failed());
+
+        assert!(bob.is_closed().await);
+        assert!(carol.is_closed().await);
+        assert!(!alice.is_closed().await);
+        assert_eq!(server.settings.read().await.server.max_players, 1);
+    }
+
+    #[tokio::test]
+    async fn ban_offline_and_online_players() {
+        let (server, addr) = start(Settings::default()).await;
+        let bob = Client::connect(&server, addr, "bob").await;
+        let bob_id = bob.id;
+        drop(bob);
+
+        assert!(wait_for(|| async { server.connected_peers().await.is_empty() }).await);
+
+        let mut alice = Client::connect(&server, addr, "alice").await;
+
+        run(&server, "ban bob alice").await;
+
+        let ids = &server.settings.read().await.ban_list.ids;
+        assert!(
+            ids.contains(&bob_id) && ids.contains(&alice.id),
+            "{:?}",
+            ids
+        );
+
+        assert!(alice
+            .recv_until(|p| matches!(p.content, Content::ChangeStage { .. }))
+            .await
+            .is_some());
     }
 }
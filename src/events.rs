@@ -0,0 +1,135 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+use uuid::Uuid;
+
+// A webhook that doesn't answer would otherwise keep its task and connection forever
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Shared by the requests, so they reuse the connections to the webhook
+pub fn webhook_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .expect("Webhook client couldn't be built")
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Connect,
+    Disconnect,
+}
+
+// Written as one json per line, so scripts can tail the output
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    pub event: EventKind,
+    pub id: Uuid,
+    pub name: &'a str,
+    pub ip: IpAddr,
+    pub ts: DateTime<Utc>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(event: EventKind, id: Uuid, name: &'a str, ip: IpAddr) -> Self {
+        Self {
+            event,
+            id,
+            name,
+            ip,
+            ts: Utc::now(),
+        }
+    }
+
+    // `-` writes to stdout, anything else is a file the event is appended to
+    pub async fn emit(&self, path: &str) {
+        let line = format!("{}\n", serde_json::to_string(self).unwrap());
+
+        if path == "-" {
+            print!("{}", line);
+            return;
+        }
+
+        if let Err(err) = append_line(path, &line).await {
+            tracing::error!(%err, "Event couldn't be written to {}", path);
+        }
+    }
+
+    // The ip isn't sent, the webhook is usually a public channel
+    pub fn post(&self, client: &reqwest::Client, url: String) {
+        let payload = WebhookPayload {
+            event: self.event,
+            id: self.id,
+            name: self.name.to_owned(),
+            ts: self.ts,
+        };
+
+        let client = client.clone();
+
+        tokio::spawn(async move {
+            let result = client
+                .post(&url)
+                .json(&payload)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+
+            if let Err(err) = result {
+                debug!(%err, "Webhook request to {} failed", url);
+            }
+        });
+    }
+}
+
+// Commands run from the console or the admin api, one json per line like the events. The
+// format is kept stable so the file can be parsed later
+#[derive(Debug, Serialize)]
+pub struct AuditEntry<'a> {
+    pub ts: DateTime<Utc>,
+    // console, or admin followed by the address of the client
+    pub source: &'a str,
+    pub command: &'a str,
+}
+
+impl<'a> AuditEntry<'a> {
+    pub fn new(source: &'a str, command: &'a str) -> Self {
+        Self {
+            ts: Utc::now(),
+            source,
+            command,
+        }
+    }
+
+    // Failures are only logged, a command still runs when it can't be audited
+    pub async fn append(&self, path: &str) {
+        let line = format!("{}\n", serde_json::to_string(self).unwrap());
+
+        if let Err(err) = append_line(path, &line).await {
+            tracing::error!(%err, "Command couldn't be written to the audit log {}", path);
+        }
+    }
+}
+
+async fn append_line(path: &str, line: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+
+    file.write_all(line.as_bytes()).await
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload {
+    event: EventKind,
+    id: Uuid,
+    name: String,
+    ts: DateTime<Utc>,
+}
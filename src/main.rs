@@ -1,18 +1,15 @@
 #![forbid(unsafe_code)]
-#![deny(private_in_public)]
 #![warn(
     clippy::all,
     clippy::dbg_macro,
     clippy::todo,
-    clippy::empty_enum,
+    clippy::empty_enums,
     clippy::enum_glob_use,
     clippy::unused_self,
     clippy::needless_continue,
     clippy::needless_borrow,
     clippy::match_wildcard_for_single_variants,
     clippy::if_let_mutex,
-    clippy::mismatched_target_os,
-    clippy::match_on_vec_items,
     clippy::imprecise_flops,
     clippy::suboptimal_flops,
     clippy::lossy_float_literal,
@@ -32,8 +29,8 @@
     missing_debug_implementations
 )]
 
-use std::net::SocketAddr;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -42,21 +39,25 @@ use color_eyre::Result;
 use once_cell::sync::Lazy;
 use owo_colors::OwoColorize;
 use server::Server;
-use settings::Settings;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
+use settings::{ConfigIssue, Settings};
 use tokio::time::sleep;
-use tracing::{debug, info};
+use tracing::info;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{fmt, EnvFilter};
 
+mod admin;
 mod commands;
+mod events;
+mod metrics;
 mod packet;
 mod peer;
 mod players;
+mod record;
 mod server;
 mod settings;
+#[cfg(test)]
+mod testing;
 
 static VERSION: Lazy<String> = Lazy::new(|| {
     let mut version = format!("v{}", env!("CARGO_PKG_VERSION"));
@@ -74,6 +75,14 @@ struct Args {
     /// Verbosity level
     #[clap(short, long, parse(from_occurrences))]
     verbose: u8,
+
+    /// Check settings.json and exit without starting the server
+    #[clap(long)]
+    check_config: bool,
+
+    /// Print the packets recorded in a file by the record setting and exit
+    #[clap(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -84,7 +93,7 @@ async fn main() -> Result<()> {
     let pkg_name = env!("TRACING_FMT");
     let filter = match args.verbose {
         #[cfg(debug_assertions)]
-        0 | 1 | 2 => format!("{}=debug", pkg_name),
+        0..=2 => format!("{}=debug", pkg_name),
 
         #[cfg(not(debug_assertions))]
         0 => format!("{}=info", pkg_name),
@@ -104,19 +113,31 @@ async fn main() -> Result<()> {
         .with(ErrorLayer::default())
         .init();
 
-    let settings = Settings::load().await;
-    let server = Arc::new(Server::new(settings));
+    if args.check_config {
+        exit(check_config().await);
+    }
 
-    let _ = server.load_shines().await;
-    let bind_address = SocketAddr::from_str(&format!(
-        "{}:{}",
-        server.settings.read().await.server.address,
-        server.settings.read().await.server.port
-    ))
-    .expect("Invalid address, please check address and port in settings.json");
+    if let Some(path) = args.replay {
+        exit(replay(&path).await);
+    }
 
-    let listener = TcpListener::bind(bind_address).await?;
+    let settings = match Settings::load().await {
+        Ok(settings) => settings,
+        Err(message) => {
+            println!(
+                "{} {}, use --check-config for details",
+                "[Error]".red(),
+                message
+            );
+            exit(1);
+        }
+    };
+    let server = Arc::new(Server::new(settings));
 
+    let _ = server.load_shines().await;
+    let _ = server.load_players().await;
+    let _ = server.load_costumes().await;
+    let _ = server.load_seed().await;
     tokio::spawn({
         let server = server.clone();
 
@@ -125,6 +146,9 @@ async fn main() -> Result<()> {
                 sleep(Duration::from_secs(120)).await;
 
                 server.sync_shine_bag().await;
+                server.persist_players().await;
+                server.persist_costumes().await;
+                server.recorder.flush().await;
             }
         }
     });
@@ -134,41 +158,117 @@ async fn main() -> Result<()> {
         async move { commands::listen(server).await }
     });
 
-    info!(addr = %bind_address, "Server ready and listening");
+    let settings = server.settings.read().await;
+    let keepalive_interval = Duration::from_secs(settings.server.keepalive_interval_secs);
+    let keepalive_timeout = Duration::from_secs(settings.server.keepalive_timeout_secs);
+    drop(settings);
+
+    if !keepalive_interval.is_zero() {
+        tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .keepalive(keepalive_interval, keepalive_timeout)
+                    .await
+            }
+        });
+    }
+
+    if server.settings.read().await.prometheus.enabled {
+        tokio::spawn({
+            let server = server.clone();
+
+            async move {
+                if let Err(err) = metrics::serve(server).await {
+                    tracing::error!(%err, "Metrics endpoint stopped");
+                }
+            }
+        });
+    }
+
+    if server.settings.read().await.admin.enabled {
+        tokio::spawn({
+            let server = server.clone();
+
+            async move {
+                if let Err(err) = admin::listen(server).await {
+                    tracing::error!(%err, "Admin api stopped");
+                }
+            }
+        });
+    }
+
+    server.listen().await?;
+
     info!(
         "Write {} or {} to get the list of the available commands",
         "help".cyan(),
         "press enter".cyan(),
     );
 
-    loop {
-        let (mut socket, _) = listener.accept().await?;
-        let server = server.clone();
+    // Connections are accepted in the tasks started by listen
+    tokio::signal::ctrl_c().await?;
 
-        tokio::spawn(async move {
-            if let Ok(addr) = socket.peer_addr() {
-                let settings = server.settings.read().await;
-                let is_banned = settings.ban_list.is_ip_ban(&addr.ip());
-                drop(settings);
+    info!("Stopping the server");
+    server.shutdown().await;
 
-                if is_banned {
-                    let _ = socket.shutdown().await;
-                    return;
-                }
+    // Returning would wait for the blocking read of stdin
+    exit(0);
+}
+
+// Returns the exit code
+async fn replay(path: &Path) -> i32 {
+    let entries = match record::read(path).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!("{} {}", "[Error]".red(), err);
+            return 1;
+        }
+    };
+
+    for (ts, header, body) in entries {
+        match header.make_packet(body) {
+            Ok(packet) => println!("{} {} {:?}", ts.to_rfc3339(), packet.id, packet.content),
+            Err(err) => println!(
+                "{} {} {} type {}: {}",
+                ts.to_rfc3339(),
+                header.id,
+                "[Malformed]".yellow(),
+                header.type_,
+                err
+            ),
+        }
+    }
+
+    0
+}
+
+// Returns the exit code
+async fn check_config() -> i32 {
+    let settings = match Settings::read().await {
+        Ok(settings) => settings,
+        Err(message) => {
+            println!("{} {}", "[Error]".red(), message);
+            return 1;
+        }
+    };
+
+    let issues = settings.validate();
+    let mut code = 0;
+
+    for issue in &issues {
+        match issue {
+            ConfigIssue::Error(message) => {
+                code = 1;
+                println!("{} {}", "[Error]".red(), message);
             }
+            ConfigIssue::Warning(message) => println!("{} {}", "[Warning]".yellow(), message),
+        }
+    }
 
-            match socket.set_nodelay(true) {
-                Ok(_) => match server.handle_connection(socket).await {
-                    Ok(_) => (),
-                    Err(message) => {
-                        debug!(error = %message, "handle_connection exited with error")
-                    }
-                },
-                Err(_) => {
-                    debug!("Couldn't set NODELAY to socket, dropping it");
-                    drop(socket)
-                }
-            };
-        });
+    if issues.is_empty() {
+        println!("settings.json is valid");
     }
+
+    code
 }
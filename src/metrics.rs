@@ -0,0 +1,285 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use color_eyre::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::packet::Content;
+use crate::server::Server;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Rejection {
+    Banned,
+    ServerFull,
+    BadHandshake,
+    Kicked,
+    NotAllowed,
+    TooManyConnections,
+}
+
+impl Rejection {
+    pub const ALL: [Self; 6] = [
+        Self::Banned,
+        Self::ServerFull,
+        Self::BadHandshake,
+        Self::Kicked,
+        Self::NotAllowed,
+        Self::TooManyConnections,
+    ];
+
+    #[inline]
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Self::Banned => "banned",
+            Self::ServerFull => "server full",
+            Self::BadHandshake => "malformed handshake",
+            Self::Kicked => "kicked",
+            Self::NotAllowed => "not allowed",
+            Self::TooManyConnections => "too many connections",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    banned: AtomicU64,
+    server_full: AtomicU64,
+    bad_handshake: AtomicU64,
+    kicked: AtomicU64,
+    not_allowed: AtomicU64,
+    too_many_connections: AtomicU64,
+    // Indexed like Content::TYPE_NAMES
+    packets_received: [AtomicU64; Content::TYPE_NAMES.len()],
+    broadcasts: AtomicU64,
+    shine_syncs: AtomicU64,
+}
+
+impl Metrics {
+    #[inline]
+    fn rejection_counter(&self, reason: Rejection) -> &AtomicU64 {
+        match reason {
+            Rejection::Banned => &self.banned,
+            Rejection::ServerFull => &self.server_full,
+            Rejection::BadHandshake => &self.bad_handshake,
+            Rejection::Kicked => &self.kicked,
+            Rejection::NotAllowed => &self.not_allowed,
+            Rejection::TooManyConnections => &self.too_many_connections,
+        }
+    }
+
+    #[inline]
+    pub fn reject(&self, reason: Rejection) {
+        self.rejection_counter(reason)
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejections(&self) -> Vec<(Rejection, u64)> {
+        Rejection::ALL
+            .into_iter()
+            .map(|reason| {
+                (
+                    reason,
+                    self.rejection_counter(reason).load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn receive(&self, content: &Content) {
+        let index = Content::TYPE_NAMES
+            .iter()
+            .position(|name| *name == content.type_name())
+            .unwrap_or_default();
+
+        self.packets_received[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn broadcast(&self) {
+        self.broadcasts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn shine_sync(&self) {
+        self.shine_syncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Prometheus text format, the gauges are read from the server by the caller
+    pub fn render(&self, connected_players: usize, shine_bag_size: usize) -> String {
+        let mut text = String::new();
+
+        let _ = writeln!(text, "# TYPE smo_connected_players gauge");
+        let _ = writeln!(text, "smo_connected_players {}", connected_players);
+
+        let _ = writeln!(text, "# TYPE smo_shine_bag_size gauge");
+        let _ = writeln!(text, "smo_shine_bag_size {}", shine_bag_size);
+
+        let _ = writeln!(text, "# TYPE smo_packets_received_total counter");
+        for (name, counter) in Content::TYPE_NAMES.iter().zip(&self.packets_received) {
+            let _ = writeln!(
+                text,
+                "smo_packets_received_total{{type=\"{}\"}} {}",
+                name,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(text, "# TYPE smo_broadcasts_total counter");
+        let _ = writeln!(
+            text,
+            "smo_broadcasts_total {}",
+            self.broadcasts.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(text, "# TYPE smo_shine_syncs_total counter");
+        let _ = writeln!(
+            text,
+            "smo_shine_syncs_total {}",
+            self.shine_syncs.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(text, "# TYPE smo_rejected_connections_total counter");
+        for (reason, count) in self.rejections() {
+            let _ = writeln!(
+                text,
+                "smo_rejected_connections_total{{reason=\"{}\"}} {}",
+                reason.to_str(),
+                count
+            );
+        }
+
+        text
+    }
+}
+
+// Minimal http server, only GET /metrics is answered
+pub async fn serve(server: Arc<Server>) -> Result<()> {
+    let settings = server.settings.read().await;
+    let address = SocketAddr::new(
+        settings.prometheus.address,
+        u16::try_from(settings.prometheus.port)?,
+    );
+    drop(settings);
+
+    let listener = TcpListener::bind(address).await?;
+
+    info!(addr = %address, "Metrics available on /metrics");
+
+    accept(listener, server).await
+}
+
+async fn accept(listener: TcpListener, server: Arc<Server>) -> Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let server = server.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(&server, socket).await {
+                debug!(error = %e, "Metrics request failed");
+            }
+        });
+    }
+}
+
+async fn handle_request(server: &Server, mut socket: TcpStream) -> Result<()> {
+    let mut buf = [0; 1024];
+    let read = socket.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..read]);
+
+    let response = if request.starts_with("GET /metrics ") {
+        let connected = server.connected_peers().await.len();
+        let shine_bag_size = server.shine_bag.read().await.len();
+        let body = server.metrics.render(connected, shine_bag_size);
+
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::accept;
+    use crate::packet::Content;
+    use crate::settings::Settings;
+    use crate::testing::{start, wait_for, Client};
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+
+        alice
+            .send(Content::Costume {
+                body: "Mario".to_owned(),
+                cap: "Mario".to_owned(),
+            })
+            .await;
+        assert!(
+            wait_for(|| async { server.players.known_costume(&alice.id).await.is_some() }).await
+        );
+
+        server
+            .shine_bag
+            .write()
+            .await
+            .extend([(1, false), (2, true)]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics = listener.local_addr().unwrap();
+        tokio::spawn(accept(listener, server.clone()));
+
+        let response = get(metrics, "/metrics").await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        for line in [
+            "smo_connected_players 1",
+            "smo_shine_bag_size 2",
+            "smo_packets_received_total{type=\"costume\"} 1",
+            "smo_rejected_connections_total{reason=\"banned\"} 0",
+        ] {
+            assert!(
+                response.lines().any(|l| l == line),
+                "{} in {}",
+                line,
+                response
+            );
+        }
+
+        assert!(get(metrics, "/")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -4,16 +4,20 @@ use std::str::from_utf8;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use color_eyre::eyre::eyre;
 use color_eyre::Result;
-use glam::{Quat, Vec3};
+use glam::{Mat4, Quat, Vec3};
 use uuid::Uuid;
 
 const ID_RANGE: Range<usize> = 0..16;
 const TYPE_RANGE: Range<usize> = 16..18;
 const SIZE_RANGE: Range<usize> = 18..20;
 pub const HEADER_SIZE: usize = 20;
-const COSTUME_SIZE: usize = 0x20;
+pub const COSTUME_SIZE: usize = 0x20;
 const STAGE_ID_SIZE: usize = 0x10;
 const STAGE_SIZE: usize = 0x30;
+const CHAT_MESSAGE_SIZE: usize = 0x80;
+// Scenarios are sent as an u8 by `Content::Game` but as an i8 by `Content::ChangeStage` (-1 lets
+// the game pick the scenario), so the values above i8::MAX are clamped wherever a scenario is
+// forwarded, see Content::clamp_scenario
 
 trait AsBytes {
     fn write_bytes(&self, bytes: &mut BytesMut);
@@ -116,15 +120,47 @@ impl ConnectionType {
     #[inline]
     fn as_u32(&self) -> u32 {
         match self {
-            Self::First => 1,
-            Self::Reconnect => 2,
+            Self::First => 0,
+            Self::Reconnect => 1,
         }
     }
 }
 
+// The handshake doesn't contain the client version, so it's inferred from the packets whose
+// layout changed between versions. Only the tag packet is known to differ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    // Tag seconds are sent as an u8
+    Legacy,
+    Current,
+}
+
+impl Protocol {
+    const LEGACY_TAG_SIZE: usize = 5;
+    const TAG_SIZE: usize = 6;
+
+    pub fn infer(type_: i16, body_size: usize) -> Option<Self> {
+        match (type_, body_size) {
+            (5, Self::LEGACY_TAG_SIZE) => Some(Self::Legacy),
+            (5, Self::TAG_SIZE) => Some(Self::Current),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Protocol {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::Current
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Content {
-    Unknown,
+    // Packet types this server doesn't handle, e.g. sent by a newer version of the client
+    Unknown {
+        type_id: i16,
+    },
     Init {
         max_player: i16,
     },
@@ -143,6 +179,7 @@ pub enum Content {
     },
     Game {
         is_2d: bool,
+        // Only values up to i8::MAX are valid scenarios, see Content::clamp_scenario
         scenario: u8,
         stage: String,
     },
@@ -165,6 +202,7 @@ pub enum Content {
     },
     Shine {
         id: i32,
+        is_grand: bool,
     },
     Capture {
         model: String,
@@ -172,54 +210,55 @@ pub enum Content {
     ChangeStage {
         id: String,
         stage: String,
+        // -1 lets the game pick the scenario
         scenario: i8,
         sub_scenario: u8,
     },
+    // Type 12, the body is the utf8 message padded with \0 to CHAT_MESSAGE_SIZE bytes. Longer
+    // messages are truncated. Only sent by the server: type 12 is the Command packet of the
+    // upstream client protocol, so the message is only displayed by the clients built to show
+    // it, see announce and whisper
+    ChatMessage {
+        message: String,
+    },
 }
 
 impl Content {
-    fn serialize_string(string: String, size: usize, buf: &mut BytesMut) {
-        let bytes = string.into_bytes();
-
-        if bytes.len() > size {
-            buf.put(bytes.take(size));
-        } else {
-            let padding: Vec<u8> = vec![0; size - bytes.len()];
+    // The game has less than a hundred scenarios, a bigger value is corrupted and would be negative
+    // once read as an i8
+    pub fn clamp_scenario(scenario: u8) -> i8 {
+        i8::try_from(scenario).unwrap_or(i8::MAX)
+    }
 
-            buf.put(&bytes[..]);
-            buf.put(&padding[..]);
-        }
+    fn serialize_string(string: String, size: usize, buf: &mut BytesMut) {
+        // Truncated on a char boundary, otherwise the string wouldn't be valid utf8 anymore
+        let end = string
+            .char_indices()
+            .map(|(i, c)| i + c.len_utf8())
+            .take_while(|end| *end <= size)
+            .last()
+            .unwrap_or(0);
+
+        let bytes = &string.as_bytes()[..end];
+        let padding: Vec<u8> = vec![0; size - bytes.len()];
+
+        buf.put(bytes);
+        buf.put(&padding[..]);
     }
 
     fn deserialize_string(bytes: Bytes) -> Result<String> {
         Ok(from_utf8(&bytes[..])?.trim_matches('\0').to_owned())
     }
 
-    fn serialize(&self) -> (Bytes, Bytes) {
+    fn serialize(&self, protocol: Protocol) -> (Bytes, Bytes) {
         let mut body = BytesMut::with_capacity(64);
-        match &self {
-            Self::Player {
-                position: _,
-                quaternion: _,
-                animation_blend_weights: _,
-                act: _,
-                subact: _,
-            } => (),
 
-            Self::Cap {
-                position: _,
-                quaternion: _,
-                cap_out: _,
-                cap_anim: _,
-            } => (),
-
-            _ => {
-                tracing::trace!(outgoing = ?self);
-            }
+        if !self.is_trace_skipped() {
+            tracing::trace!(outgoing = ?self);
         }
 
         let id = match self {
-            Self::Unknown => 0i16,
+            Self::Unknown { type_id } => *type_id,
             Self::Init { max_player } => {
                 body.put_i16_le(*max_player);
 
@@ -275,7 +314,12 @@ impl Content {
             } => {
                 body.put_u8(*update_type);
                 body.put_u8(is_it.as_byte());
-                body.put_u16_le(*seconds);
+
+                match protocol {
+                    Protocol::Legacy => body.put_u8(u8::try_from(*seconds).unwrap_or(u8::MAX)),
+                    Protocol::Current => body.put_u16_le(*seconds),
+                }
+
                 body.put_u16_le(*minutes);
 
                 5
@@ -299,8 +343,9 @@ impl Content {
                 Self::serialize_string(cap.clone(), COSTUME_SIZE, &mut body);
                 8
             }
-            Self::Shine { id } => {
+            Self::Shine { id, is_grand } => {
                 body.put_i32_le(*id);
+                body.put_u8(is_grand.as_byte());
                 9
             }
             Self::Capture { model } => {
@@ -320,6 +365,11 @@ impl Content {
                 body.put_u8(*sub_scenario);
                 11
             }
+            Self::ChatMessage { message } => {
+                Self::serialize_string(message.clone(), CHAT_MESSAGE_SIZE, &mut body);
+
+                12
+            }
         };
 
         let id = id.to_le_bytes().to_vec();
@@ -327,7 +377,38 @@ impl Content {
         (Bytes::from(id), body.into())
     }
 
+    // Smallest body each packet type can be read from, the bodies can be longer
+    fn min_body_size(id: i16) -> usize {
+        match id {
+            1 => 2,
+            2 => 56,
+            3 => 29 + 0x30,
+            4 => 0x42,
+            6 => COSTUME_SIZE,
+            8 => COSTUME_SIZE * 2,
+            9 => 4,
+            10 => COSTUME_SIZE,
+            11 => STAGE_SIZE + STAGE_ID_SIZE + 2,
+            12 => CHAT_MESSAGE_SIZE,
+            _ => 0,
+        }
+    }
+
+    // Biggest of the sizes above, a smaller server.max_packet_size would disconnect everyone
+    pub const LARGEST_BODY_SIZE: usize = CHAT_MESSAGE_SIZE;
+
     fn deserialize(id: i16, body: Bytes) -> Result<Self> {
+        let min_size = Self::min_body_size(id);
+
+        if body.len() < min_size {
+            return Err(eyre!(
+                "Packet of type {} is {} bytes, expected at least {}",
+                id,
+                body.len(),
+                min_size
+            ));
+        }
+
         let packet = match id {
             1 => Self::Init {
                 max_player: i16::from_le_bytes(body[..].try_into()?),
@@ -354,23 +435,26 @@ impl Content {
                 scenario: body.slice(1..2).get_u8(),
                 stage: Self::deserialize_string(body.slice(2..0x42))?,
             },
-            5 => {
-                if body.len() == 5 {
-                    Self::Tag {
-                        update_type: body.slice(0..1).get_u8(),
-                        is_it: body.slice(1..2).get_u8().as_bool(),
-                        seconds: u16::from(body.slice(2..3).get_u8()),
-                        minutes: body.slice(3..5).get_u16_le(),
-                    }
-                } else {
-                    Self::Tag {
-                        update_type: body.slice(0..1).get_u8(),
-                        is_it: body.slice(1..2).get_u8().as_bool(),
-                        seconds: body.slice(2..4).get_u16_le(),
-                        minutes: body.slice(4..6).get_u16_le(),
-                    }
+            5 => match Protocol::infer(id, body.len()) {
+                Some(Protocol::Legacy) => Self::Tag {
+                    update_type: body.slice(0..1).get_u8(),
+                    is_it: body.slice(1..2).get_u8().as_bool(),
+                    seconds: u16::from(body.slice(2..3).get_u8()),
+                    minutes: body.slice(3..5).get_u16_le(),
+                },
+                Some(Protocol::Current) => Self::Tag {
+                    update_type: body.slice(0..1).get_u8(),
+                    is_it: body.slice(1..2).get_u8().as_bool(),
+                    seconds: body.slice(2..4).get_u16_le(),
+                    minutes: body.slice(4..6).get_u16_le(),
+                },
+                None => {
+                    return Err(eyre!(
+                        "Unsupported tag packet of {} bytes, the client version isn't supported",
+                        body.len()
+                    ));
                 }
-            }
+            },
             6 => Self::Connect {
                 type_: ConnectionType::from_u32(body.slice(0..4).get_u32_le())?,
                 max_player: body.slice(4..6).get_u16_le(),
@@ -383,6 +467,8 @@ impl Content {
             },
             9 => Self::Shine {
                 id: body.slice(..4).get_i32_le(),
+                // Older clients only send the id
+                is_grand: body.len() > 4 && body.slice(4..5).get_u8().as_bool(),
             },
             10 => Self::Capture {
                 model: Self::deserialize_string(body.slice(0..COSTUME_SIZE))?,
@@ -397,31 +483,124 @@ impl Content {
                     .slice((STAGE_SIZE + STAGE_ID_SIZE + 1)..(STAGE_SIZE + STAGE_ID_SIZE + 2))
                     .get_u8(),
             },
-            _ => Self::Unknown,
+            12 => Self::ChatMessage {
+                message: Self::deserialize_string(body.slice(0..CHAT_MESSAGE_SIZE))?,
+            },
+            type_id => Self::Unknown { type_id },
         };
 
-        match &packet {
+        if !packet.is_trace_skipped() {
+            tracing::trace!(incoming = ?packet);
+        }
+
+        Ok(packet)
+    }
+
+    // Packets sent many times per second, tracing them would hide everything else
+    const TRACE_SKIPPED_TYPES: [&'static str; 4] = ["player", "cap", "costume", "capture"];
+
+    #[inline]
+    fn is_trace_skipped(&self) -> bool {
+        Self::TRACE_SKIPPED_TYPES.contains(&self.type_name())
+    }
+
+    pub const TYPE_NAMES: [&'static str; 13] = [
+        "unknown",
+        "init",
+        "player",
+        "cap",
+        "game",
+        "tag",
+        "connect",
+        "disconnect",
+        "costume",
+        "shine",
+        "capture",
+        "changestage",
+        "chatmessage",
+    ];
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Unknown { type_id: _ } => "unknown",
+            Self::Init { max_player: _ } => "init",
             Self::Player {
                 position: _,
                 quaternion: _,
                 animation_blend_weights: _,
                 act: _,
                 subact: _,
-            } => (),
-
+            } => "player",
             Self::Cap {
                 position: _,
                 quaternion: _,
                 cap_out: _,
                 cap_anim: _,
-            } => (),
+            } => "cap",
+            Self::Game {
+                is_2d: _,
+                scenario: _,
+                stage: _,
+            } => "game",
+            Self::Tag {
+                update_type: _,
+                is_it: _,
+                seconds: _,
+                minutes: _,
+            } => "tag",
+            Self::Connect {
+                type_: _,
+                max_player: _,
+                client: _,
+            } => "connect",
+            Self::Disconnect => "disconnect",
+            Self::Costume { body: _, cap: _ } => "costume",
+            Self::Shine { id: _, is_grand: _ } => "shine",
+            Self::Capture { model: _ } => "capture",
+            Self::ChangeStage {
+                id: _,
+                stage: _,
+                scenario: _,
+                sub_scenario: _,
+            } => "changestage",
+            Self::ChatMessage { message: _ } => "chatmessage",
+        }
+    }
 
-            _ => {
-                tracing::trace!(incoming = ?packet);
+    // Returns the player upside down, `size` above its real position. Clients can send quaternions
+    // that aren't normalized, so `None` is returned if the result isn't usable
+    pub fn flipped(&self, size: f32) -> Option<Self> {
+        match self {
+            Self::Player {
+                position,
+                quaternion,
+                animation_blend_weights,
+                act,
+                subact,
+            } => {
+                if !quaternion.is_finite() || quaternion.length_squared() <= f32::EPSILON {
+                    return None;
+                }
+
+                let position = *position + Vec3::Y * size;
+                let quaternion = quaternion.normalize()
+                    * Quat::from_mat4(&Mat4::from_rotation_x(std::f32::consts::PI))
+                    * Quat::from_mat4(&Mat4::from_rotation_y(std::f32::consts::PI));
+
+                if !position.is_finite() || !quaternion.is_finite() {
+                    return None;
+                }
+
+                Some(Self::Player {
+                    position,
+                    quaternion,
+                    animation_blend_weights: animation_blend_weights.clone(),
+                    act: *act,
+                    subact: *subact,
+                })
             }
+            _ => None,
         }
-
-        Ok(packet)
     }
 
     #[inline]
@@ -454,10 +633,10 @@ impl Packet {
         Self { id, content }
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
+    pub fn as_bytes(&self, protocol: Protocol) -> Vec<u8> {
         let id: &[u8] = &self.id.into_bytes();
 
-        let (type_, body) = self.content.serialize();
+        let (type_, body) = self.content.serialize(protocol);
 
         let body = &body[..];
 
@@ -478,7 +657,9 @@ impl Header {
     pub fn from_bytes(bytes: Bytes) -> Result<Self> {
         let b_id = bytes.slice(ID_RANGE)[..].try_into()?;
         let type_ = bytes.slice(TYPE_RANGE).get_i16_le();
-        let packet_size = bytes.slice(SIZE_RANGE).get_i16_le() as usize;
+        let packet_size = bytes.slice(SIZE_RANGE).get_i16_le();
+        let packet_size = usize::try_from(packet_size)
+            .map_err(|_| eyre!("Invalid packet size {}", packet_size))?;
 
         Ok(Self {
             id: Uuid::from_bytes(b_id),
@@ -493,3 +674,167 @@ impl Header {
         Ok(packet)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::{Quat, Vec3};
+
+    use bytes::BytesMut;
+
+    use super::{ConnectionType, Content, Protocol, COSTUME_SIZE};
+
+    fn round_trip(content: &Content) -> Content {
+        let (id, body) = content.serialize(Protocol::Current);
+
+        Content::deserialize(i16::from_le_bytes([id[0], id[1]]), body).unwrap()
+    }
+
+    #[test]
+    fn shine_round_trip() {
+        for is_grand in [false, true] {
+            let shine = round_trip(&Content::Shine { id: 42, is_grand });
+
+            assert!(
+                matches!(shine, Content::Shine { id: 42, is_grand: read } if read == is_grand),
+                "{:?}",
+                shine
+            );
+        }
+    }
+
+    #[test]
+    fn connect_round_trip() {
+        for type_ in [ConnectionType::First, ConnectionType::Reconnect] {
+            let connect = round_trip(&Content::Connect {
+                type_: type_.clone(),
+                max_player: 8,
+                client: "alice".to_owned(),
+            });
+
+            match connect {
+                Content::Connect {
+                    type_: read,
+                    max_player: 8,
+                    client,
+                } => {
+                    assert_eq!(read.as_u32(), type_.as_u32());
+                    assert_eq!(client, "alice");
+                }
+                content => panic!("{:?}", content),
+            }
+        }
+    }
+
+    #[test]
+    fn scenario_is_clamped_to_i8() {
+        assert_eq!(Content::clamp_scenario(0), 0);
+        assert_eq!(Content::clamp_scenario(127), 127);
+        assert_eq!(Content::clamp_scenario(128), 127);
+        assert_eq!(Content::clamp_scenario(u8::MAX), 127);
+
+        for scenario in [127, 128] {
+            let game = round_trip(&Content::Game {
+                is_2d: false,
+                scenario,
+                stage: "CapWorldHomeStage".to_owned(),
+            });
+
+            assert!(
+                matches!(game, Content::Game { scenario: read, .. } if read == scenario),
+                "{:?}",
+                game
+            );
+        }
+    }
+
+    fn player(quaternion: Quat) -> Content {
+        Content::Player {
+            position: Vec3::ZERO,
+            quaternion,
+            animation_blend_weights: vec![0.; 6],
+            act: 0,
+            subact: 0,
+        }
+    }
+
+    #[test]
+    fn unusable_quaternions_are_not_flipped() {
+        for quaternion in [
+            Quat::from_xyzw(0., 0., 0., 0.),
+            Quat::from_xyzw(f32::NAN, 0., 0., 1.),
+        ] {
+            assert!(player(quaternion).flipped(100.).is_none());
+        }
+
+        let flipped = player(Quat::from_xyzw(0., 0., 0., 2.)).flipped(100.);
+
+        assert!(
+            matches!(flipped, Some(Content::Player { position, quaternion, .. })
+                if position == Vec3::new(0., 100., 0.) && quaternion.is_normalized()),
+            "{:?}",
+            flipped
+        );
+    }
+
+    #[test]
+    fn strings_are_truncated_on_a_char_boundary() {
+        // The last char starts at the last byte, so a byte cut would split it
+        let name = format!("{}é", "a".repeat(COSTUME_SIZE - 1));
+        let mut buf = BytesMut::new();
+
+        Content::serialize_string(name, COSTUME_SIZE, &mut buf);
+
+        assert_eq!(buf.len(), COSTUME_SIZE);
+
+        let costume = round_trip(&Content::Costume {
+            body: format!("{}é", "a".repeat(COSTUME_SIZE - 1)),
+            cap: "ééé".to_owned(),
+        });
+
+        assert!(
+            matches!(&costume, Content::Costume { body, cap }
+                if *body == "a".repeat(COSTUME_SIZE - 1) && cap == "ééé"),
+            "{:?}",
+            costume
+        );
+    }
+
+    #[test]
+    fn shine_without_grand_flag() {
+        let shine = Content::deserialize(9, 42i32.to_le_bytes().to_vec().into()).unwrap();
+
+        assert!(matches!(
+            shine,
+            Content::Shine {
+                id: 42,
+                is_grand: false
+            }
+        ));
+    }
+
+    #[test]
+    fn short_bodies_are_rejected() {
+        for id in 0..Content::TYPE_NAMES.len() as i16 {
+            let min_size = Content::min_body_size(id);
+
+            if min_size > 0 {
+                assert!(
+                    Content::deserialize(id, vec![0; min_size - 1].into()).is_err(),
+                    "type {} accepted {} bytes",
+                    id,
+                    min_size - 1
+                );
+            }
+
+            // Must not panic either, the result depends on the type
+            let _ = Content::deserialize(id, vec![0; min_size].into());
+        }
+    }
+
+    #[test]
+    fn largest_body_size_covers_every_type() {
+        for id in 0..Content::TYPE_NAMES.len() as i16 {
+            assert!(Content::min_body_size(id) <= Content::LARGEST_BODY_SIZE);
+        }
+    }
+}
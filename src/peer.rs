@@ -1,43 +1,147 @@
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
+use color_eyre::Result;
 use tokio::io::{AsyncWriteExt, WriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
+use tracing::{debug, Instrument, Span};
 use uuid::Uuid;
 
-use crate::packet::Packet;
+use crate::packet::{Packet, Protocol};
 
 #[derive(Debug)]
 pub struct Peer {
     pub id: Uuid,
     pub ip: IpAddr,
     pub connected: bool,
+    pub connected_at: Instant,
+    // Only receives, see settings::Spectators
+    pub spectator: bool,
+    // Milliseconds between connected_at and the last packet received
+    last_activity: AtomicU64,
+    // Inferred from the packets received, see Protocol
+    legacy_protocol: AtomicBool,
     socket: Mutex<WriteHalf<TcpStream>>,
+    // Span of the connection, so the packets sent from other connections are logged in it
+    span: Span,
 }
 
 // Player -> Player
 // State related stuff -> Game state: Arc<RwLock<HashMap<Uuid, RwLock<State>>>>
 impl Peer {
     #[inline]
-    pub fn new(ip: IpAddr, socket: WriteHalf<TcpStream>) -> Self {
+    pub fn new(ip: IpAddr, socket: WriteHalf<TcpStream>, span: Span) -> Self {
         Self {
             id: Uuid::nil(),
             ip,
             connected: true,
+            connected_at: Instant::now(),
+            spectator: false,
+            last_activity: AtomicU64::new(0),
+            legacy_protocol: AtomicBool::new(false),
             socket: Mutex::new(socket),
+            span,
         }
     }
 
+    #[inline]
+    pub fn touch(&self) {
+        let elapsed = self.connected_at.elapsed().as_millis();
+
+        self.last_activity.store(
+            u64::try_from(elapsed).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    #[inline]
+    pub fn last_activity(&self) -> Instant {
+        self.connected_at + Duration::from_millis(self.last_activity.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub fn protocol(&self) -> Protocol {
+        if self.legacy_protocol.load(Ordering::Relaxed) {
+            Protocol::Legacy
+        } else {
+            Protocol::Current
+        }
+    }
+
+    // Returns true if the protocol changed
+    #[inline]
+    pub fn set_protocol(&self, protocol: Protocol) -> bool {
+        let legacy = protocol == Protocol::Legacy;
+
+        self.legacy_protocol.swap(legacy, Ordering::Relaxed) != legacy
+    }
+
     pub async fn disconnect(&self) {
         let mut socket = self.socket.lock().await;
 
+        debug!(parent: &self.span, "Closing the connection");
+
         // TODO: Handle error
         let _ = socket.shutdown().await;
     }
 
     pub async fn send(&self, packet: Packet) {
-        let mut socket = self.socket.lock().await;
+        let _ = self.try_send(packet).await;
+    }
+
+    pub async fn try_send(&self, packet: Packet) -> Result<()> {
+        // Logged in the span of the receiver, even when it's sent from another connection
+        async {
+            let mut socket = self.socket.lock().await;
+
+            socket.write_all(&packet.as_bytes(self.protocol())).await
+        }
+        .instrument(self.span.clone())
+        .await?;
+
+        Ok(())
+    }
+}
+
+// Token bucket allowing bursts of up to one second worth of packets
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            per_second: f64::from(per_second),
+            tokens: f64::from(per_second),
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Returns false if the packet is over the limit, a limit of 0 allows everything
+    pub fn allow(&mut self) -> bool {
+        if self.per_second == 0. {
+            return true;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+
+        self.tokens = elapsed
+            .mul_add(self.per_second, self.tokens)
+            .min(self.per_second);
+
+        if self.tokens < 1. {
+            return false;
+        }
+
+        self.tokens -= 1.;
 
-        let _ = socket.write_all(&packet.as_bytes()).await;
+        true
     }
 }
@@ -1,8 +1,10 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-use chrono::Duration;
+use chrono::{DateTime, Duration, Utc};
 use futures::future::join_all;
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
@@ -10,8 +12,31 @@ use crate::packet::{Content, Packet};
 
 const MARIO_SIZE: f32 = 160.;
 const MARIO_SIZE_2D: f32 = 180.;
+// Farther than that from the previous position is a teleport, e.g. a pipe, it isn't smoothed
+const SMOOTHING_SNAP_DISTANCE: f32 = 1000.;
+
+// When seekers are hidden, hiders still see them within reveal_radius. 0 hides them everywhere
+pub fn is_seeker_revealed(seeker: Option<Vec3>, hider: Option<Vec3>, reveal_radius: f32) -> bool {
+    match (seeker, hider) {
+        (Some(seeker), Some(hider)) if reveal_radius > 0. => {
+            seeker.distance(hider) <= reveal_radius
+        }
+        _ => false,
+    }
+}
+
+// Moves from the previous position toward the current one, smoothing is the part of the
+// previous position that is kept, between 0 (no smoothing) and 1 (never moves)
+pub fn smooth_position(previous: Option<Vec3>, current: Vec3, smoothing: f32) -> Vec3 {
+    match previous {
+        Some(previous) if previous.distance(current) <= SMOOTHING_SNAP_DISTANCE => {
+            previous.lerp(current, 1. - smoothing)
+        }
+        _ => current,
+    }
+}
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct Costume {
     pub body: String,
     pub cap: String,
@@ -28,8 +53,17 @@ pub struct Player {
     pub is_seeking: bool,
     pub last_game_packet: Option<Packet>,
     pub last_position: Option<Content>,
+    // Cleared when changing stage, it's only relevant in the stage it was thrown
+    pub last_cap: Option<Packet>,
+    // Model of the enemy the player is capturing
+    pub captured: Option<String>,
+    // Sent to the others instead of the player's movement while it's set, see freeze
+    pub frozen_position: Option<Content>,
+    // Last position sent to the others when server.position_smoothing is set, cleared when
+    // changing stage
+    pub smoothed_position: Option<Vec3>,
     // id, is_grand
-    pub shine_sync: HashSet<i32>,
+    pub shine_sync: HashSet<(i32, bool)>,
     pub loaded_save: bool,
     pub time: Duration,
 }
@@ -46,6 +80,10 @@ impl Default for Player {
             is_seeking: Default::default(),
             last_game_packet: Default::default(),
             last_position: Default::default(),
+            last_cap: Default::default(),
+            captured: Default::default(),
+            frozen_position: Default::default(),
+            smoothed_position: Default::default(),
             shine_sync: Default::default(),
             loaded_save: Default::default(),
             time: Duration::seconds(0),
@@ -66,6 +104,10 @@ impl Player {
             is_seeking: false,
             last_game_packet: None,
             last_position: None,
+            last_cap: None,
+            captured: None,
+            frozen_position: None,
+            smoothed_position: None,
             shine_sync: HashSet::new(),
             loaded_save: false,
             time: Duration::zero(),
@@ -88,6 +130,20 @@ impl Player {
         }
     }
 
+    // Position of the last Content::Player packet, None until the player moved
+    pub fn last_position(&self) -> Option<Vec3> {
+        match &self.last_position {
+            Some(Content::Player {
+                position,
+                quaternion: _,
+                animation_blend_weights: _,
+                act: _,
+                subact: _,
+            }) => Some(*position),
+            _ => None,
+        }
+    }
+
     pub fn get_stage(&self) -> Option<String> {
         self.last_game_packet
             .as_ref()
@@ -102,10 +158,30 @@ impl Player {
     }
 }
 
+// Players that joined the server at least once, kept even when they're not connected anymore
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KnownPlayer {
+    pub name: String,
+    pub last_seen: DateTime<Utc>,
+}
+
+// Costume of a player that may have left, saved in persist_costumes.file_name
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KnownCostume {
+    #[serde(flatten)]
+    pub costume: Costume,
+    // Missing from the files saved before the costumes were forgotten
+    #[serde(default = "Utc::now")]
+    pub last_seen: DateTime<Utc>,
+}
+
 pub type SharedPlayer = Arc<RwLock<Player>>;
 pub struct Players {
     players: RwLock<HashMap<Uuid, SharedPlayer>>,
     names: RwLock<HashMap<Uuid, String>>,
+    known: RwLock<HashMap<Uuid, KnownPlayer>>,
+    // Restored when the player comes back so they don't appear with the default costume
+    costumes: RwLock<HashMap<Uuid, KnownCostume>>,
 }
 
 impl Players {
@@ -114,6 +190,8 @@ impl Players {
         Self {
             players: RwLock::default(),
             names: RwLock::default(),
+            known: RwLock::default(),
+            costumes: RwLock::default(),
         }
     }
 
@@ -159,26 +237,68 @@ impl Players {
             .collect()
     }
 
+    // The smallest id is returned when several players have the same name, see get_all_ids_by_name
     pub async fn get_id_by_name(&self, username: String) -> Option<Uuid> {
+        self.get_all_ids_by_name(&username).await.into_iter().next()
+    }
+
+    // Sorted, so the same player comes first whatever the order of the HashMap
+    pub async fn get_all_ids_by_name(&self, username: &str) -> Vec<Uuid> {
         let names = self.names.read().await;
 
-        names
+        let mut ids: Vec<Uuid> = names
             .iter()
-            .find(|(_, name)| name.to_lowercase() == username.to_lowercase())
+            .filter(|(_, name)| name.to_lowercase() == username.to_lowercase())
             .map(|(id, _)| *id)
+            .collect();
+
+        ids.sort();
+
+        ids
     }
 
-    // No idea when to remove a player for now
-    // pub async fn remove(&self, id: &Uuid) -> Option<SharedPlayer> {
-    //     let mut players = self.players.write().await;
+    // Refuses a name already used by another player, so a rename never adds a duplicate that
+    // get_id_by_name would have to pick from
+    pub async fn rename(&self, id: &Uuid, name: String) -> Result<(), String> {
+        let player = self
+            .get(id)
+            .await
+            .ok_or_else(|| format!("Couldn't find player {}", id))?;
 
-    //     players.remove(id)
-    // }
+        let mut names = self.names.write().await;
+
+        if names.iter().any(|(other, other_name)| {
+            other != id && other_name.to_lowercase() == name.to_lowercase()
+        }) {
+            return Err(format!("{} is already used by another player", name));
+        }
+
+        names.insert(*id, name.clone());
+        drop(names);
+
+        if let Some(known) = self.known.write().await.get_mut(id) {
+            known.name = name.clone();
+        }
+
+        player.write().await.name = name;
+
+        Ok(())
+    }
+
+    // The player stays in known, so its name and costume are restored when it comes back
+    pub async fn remove(&self, id: &Uuid) -> Option<SharedPlayer> {
+        let mut players = self.players.write().await;
+        let mut names = self.names.write().await;
+
+        names.remove(id);
+
+        players.remove(id)
+    }
 
     pub async fn get_last_game_packets(&self) -> Vec<Packet> {
         let players = self.players.read().await;
 
-        let players = join_all(players.iter().map(|(_, p)| p.read())).await;
+        let players = join_all(players.values().map(|p| p.read())).await;
 
         players
             .iter()
@@ -202,4 +322,115 @@ impl Players {
 
         player_ref
     }
+
+    pub async fn see(&self, id: Uuid, name: String) {
+        let mut known = self.known.write().await;
+
+        let player = known.entry(id).or_insert_with(|| KnownPlayer {
+            name: name.clone(),
+            last_seen: Utc::now(),
+        });
+
+        player.name = name;
+        player.last_seen = Utc::now();
+    }
+
+    pub async fn remember_costume(&self, id: Uuid, costume: Costume) {
+        self.costumes.write().await.insert(
+            id,
+            KnownCostume {
+                costume,
+                last_seen: Utc::now(),
+            },
+        );
+    }
+
+    pub async fn forget_costume(&self, id: &Uuid) {
+        self.costumes.write().await.remove(id);
+    }
+
+    pub async fn known_costume(&self, id: &Uuid) -> Option<Costume> {
+        self.costumes
+            .read()
+            .await
+            .get(id)
+            .map(|known| known.costume.clone())
+    }
+
+    pub async fn costumes(&self) -> HashMap<Uuid, KnownCostume> {
+        self.costumes.read().await.clone()
+    }
+
+    pub async fn set_costumes(&self, costumes: HashMap<Uuid, KnownCostume>) {
+        *self.costumes.write().await = costumes;
+    }
+
+    pub async fn known(&self) -> HashMap<Uuid, KnownPlayer> {
+        self.known.read().await.clone()
+    }
+
+    pub async fn set_known(&self, players: HashMap<Uuid, KnownPlayer>) {
+        *self.known.write().await = players;
+    }
+
+    pub async fn find_known(&self, id: Option<Uuid>, username: &str) -> Vec<(Uuid, KnownPlayer)> {
+        let known = self.known.read().await;
+
+        known
+            .iter()
+            .filter(|(known_id, player)| {
+                Some(**known_id) == id || player.name.to_lowercase() == username.to_lowercase()
+            })
+            .map(|(id, player)| (*id, player.clone()))
+            .collect()
+    }
+
+    pub async fn forget_older_than(&self, ttl: Duration) -> usize {
+        let mut known = self.known.write().await;
+        let count = known.len();
+
+        known.retain(|_, player| Utc::now() - player.last_seen < ttl);
+
+        count - known.len()
+    }
+
+    pub async fn forget_costumes_older_than(&self, ttl: Duration) -> usize {
+        let mut costumes = self.costumes.write().await;
+        let count = costumes.len();
+
+        costumes.retain(|_, costume| Utc::now() - costume.last_seen < ttl);
+
+        count - costumes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::smooth_position;
+
+    #[test]
+    fn smoothing_interpolates_the_positions() {
+        let previous = Vec3::ZERO;
+        let current = Vec3::new(10., 20., -30.);
+
+        assert_eq!(smooth_position(Some(previous), current, 0.), current);
+        assert_eq!(smooth_position(Some(previous), current, 1.), previous);
+        assert_eq!(
+            smooth_position(Some(previous), current, 0.5),
+            Vec3::new(5., 10., -15.)
+        );
+    }
+
+    #[test]
+    fn smoothing_snaps_without_a_close_previous_position() {
+        let current = Vec3::new(10., 20., -30.);
+
+        assert_eq!(smooth_position(None, current, 0.5), current);
+        assert_eq!(
+            smooth_position(Some(Vec3::new(5000., 0., 0.)), current, 0.5),
+            current
+        );
+    }
 }
@@ -0,0 +1,103 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bytes::{Buf, Bytes};
+use chrono::{DateTime, TimeZone, Utc};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+
+use crate::packet::{Header, HEADER_SIZE};
+
+// Size of the reception time written before each packet
+const TS_SIZE: usize = 8;
+
+// Each entry is the size of what follows as an u32, the reception time in milliseconds since the
+// unix epoch as an i64 then the packet as it was received, header and body. Everything is little
+// endian, like the packets
+#[derive(Debug, Default)]
+pub struct Recorder {
+    // Opened when the first packet is recorded
+    file: Mutex<Option<BufWriter<File>>>,
+    // Set when the file can't be written, so the error isn't logged for every packet
+    failed: AtomicBool,
+}
+
+impl Recorder {
+    pub async fn write(&self, path: &str, header: &Header, body: &Bytes) {
+        if self.failed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Err(err) = self.try_write(path, header, body).await {
+            self.failed.store(true, Ordering::Relaxed);
+            tracing::error!(%err, "Packets couldn't be recorded to {}, recording stopped", path);
+        }
+    }
+
+    async fn try_write(&self, path: &str, header: &Header, body: &Bytes) -> Result<()> {
+        let mut file = self.file.lock().await;
+
+        let writer = match &mut *file {
+            Some(writer) => writer,
+            None => {
+                let opened = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+
+                file.insert(BufWriter::new(opened))
+            }
+        };
+
+        let size = TS_SIZE + HEADER_SIZE + body.len();
+
+        writer.write_u32_le(u32::try_from(size)?).await?;
+        writer.write_i64_le(Utc::now().timestamp_millis()).await?;
+        writer.write_all(header.id.as_bytes()).await?;
+        writer.write_i16_le(header.type_).await?;
+        writer.write_i16_le(i16::try_from(body.len())?).await?;
+        writer.write_all(body).await?;
+
+        Ok(())
+    }
+
+    pub async fn flush(&self) {
+        if let Some(writer) = &mut *self.file.lock().await {
+            let _ = writer.flush().await;
+        }
+    }
+}
+
+// The bodies aren't parsed, so the malformed packets can be inspected too
+pub async fn read(path: &Path) -> Result<Vec<(DateTime<Utc>, Header, Bytes)>> {
+    let mut bytes = Bytes::from(tokio::fs::read(path).await?);
+    let mut entries = vec![];
+
+    while bytes.has_remaining() {
+        if bytes.remaining() < 4 {
+            return Err(eyre!("Truncated entry after {} packets", entries.len()));
+        }
+
+        let size = bytes.get_u32_le() as usize;
+
+        if size < TS_SIZE + HEADER_SIZE || bytes.remaining() < size {
+            return Err(eyre!("Truncated entry after {} packets", entries.len()));
+        }
+
+        let mut entry = bytes.split_to(size);
+        let ts = entry.get_i64_le();
+        let ts = Utc
+            .timestamp_millis_opt(ts)
+            .single()
+            .ok_or_else(|| eyre!("Invalid time {} after {} packets", ts, entries.len()))?;
+        let header = Header::from_bytes(entry.split_to(HEADER_SIZE))?;
+
+        entries.push((ts, header, entry));
+    }
+
+    Ok(entries)
+}
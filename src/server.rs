@@ -1,5 +1,8 @@
 use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use bytes::Bytes;
 use chrono::Duration;
@@ -7,25 +10,59 @@ use color_eyre::eyre::eyre;
 use color_eyre::Result;
 use futures::future::join_all;
 use futures::Future;
-use glam::{Mat4, Quat, Vec3};
+use glam::Vec3;
+use socket2::{Domain, Socket, Type};
 use tokio::fs::OpenOptions;
-use tokio::io::{split, AsyncReadExt, ReadHalf};
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
-use tokio::time::sleep;
-use tracing::{debug, info};
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tracing::field::{display, Empty};
+use tracing::{debug, info, info_span, trace, warn, Instrument, Span};
 use uuid::Uuid;
 
-use crate::packet::{ConnectionType, Content, Header, Packet, TagUpdate, HEADER_SIZE};
-use crate::peer::Peer;
-use crate::players::{Player, Players, SharedPlayer};
-use crate::settings::Settings;
+use crate::commands::Stage;
+use crate::events::{webhook_client, AuditEntry, Event, EventKind};
+use crate::metrics::{Metrics, Rejection};
+use crate::packet::{ConnectionType, Content, Header, Packet, Protocol, TagUpdate, HEADER_SIZE};
+use crate::peer::{Peer, RateLimiter};
+use crate::players::{is_seeker_revealed, smooth_position, Costume, Player, Players, SharedPlayer};
+use crate::record::Recorder;
+use crate::settings::{SeedAction, Settings, SettingsSnapshot};
 
 pub struct Server {
     pub peers: RwLock<HashMap<Uuid, Peer>>,
-    pub shine_bag: RwLock<HashSet<i32>>,
+    pub shine_bag: RwLock<HashSet<(i32, bool)>>,
     pub players: Players,
     pub settings: RwLock<Settings>,
+    pub metrics: Metrics,
+    // Packets from players are not relayed while it's true
+    pub broadcasting_paused: AtomicBool,
+    // Actions of seed.json by player name, removed once applied
+    pub pending_rules: RwLock<HashMap<String, Vec<SeedAction>>>,
+    // (seeker, hider) closer than tag.proximity_radius
+    near_players: RwLock<HashSet<(Uuid, Uuid)>>,
+    // Kicked players and when they were kicked
+    kicked: RwLock<HashMap<Uuid, Instant>>,
+    // (target, observer), the observer doesn't receive the movement of the target
+    pub hidden: RwLock<HashSet<(Uuid, Uuid)>>,
+    // Set when the moons file can't be opened, so it isn't written for the rest of the session.
+    // The setting isn't changed, otherwise saving the settings would disable it for good
+    shine_persistence_failed: AtomicBool,
+    // Accept loops of the bound addresses, replaced when restarting with new addresses
+    listeners: Mutex<Vec<(SocketAddr, JoinHandle<()>)>>,
+    // Waiting to assign the tag roles, aborted by tag stop or when another round is started
+    pub tag_start: Mutex<Option<JoinHandle<()>>>,
+    // Seconds left before the tag roles are assigned, kept while the countdown is paused
+    pub tag_countdown: AtomicU32,
+    pub tag_countdown_paused: AtomicBool,
+    // Timers of the players taking part in the countdown, which overwrites them, restored when
+    // it ends or is stopped
+    tag_saved_times: Mutex<HashMap<Uuid, Duration>>,
+    // Packets received while record is enabled
+    pub recorder: Recorder,
+    webhook_client: reqwest::Client,
 }
 
 impl Server {
@@ -35,34 +72,150 @@ impl Server {
             shine_bag: RwLock::default(),
             players: Players::new(),
             settings: RwLock::new(settings),
+            metrics: Metrics::default(),
+            broadcasting_paused: AtomicBool::new(false),
+            pending_rules: RwLock::default(),
+            near_players: RwLock::default(),
+            kicked: RwLock::default(),
+            hidden: RwLock::default(),
+            shine_persistence_failed: AtomicBool::new(false),
+            listeners: Mutex::default(),
+            tag_start: Mutex::default(),
+            tag_countdown: AtomicU32::new(0),
+            tag_countdown_paused: AtomicBool::new(false),
+            tag_saved_times: Mutex::default(),
+            recorder: Recorder::default(),
+            webhook_client: webhook_client(),
+        }
+    }
+
+    pub async fn settings_snapshot(&self) -> SettingsSnapshot {
+        SettingsSnapshot::from(&*self.settings.read().await)
+    }
+
+    // Server packets use a nil id and are never blocked
+    async fn is_relay_blocked(&self, packet: &Packet) -> bool {
+        if packet.id.is_nil() {
+            return false;
+        }
+
+        if self.broadcasting_paused.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        if !self
+            .settings
+            .read()
+            .await
+            .is_packet_blocked(&packet.content)
+        {
+            return false;
+        }
+
+        trace!(id = %packet.id, type_ = packet.content.type_name(), "Not relaying blocked packet");
+
+        true
+    }
+
+    // Players that shouldn't receive the packet because of a hide rule
+    async fn hidden_from(&self, packet: &Packet) -> HashSet<Uuid> {
+        match packet.content {
+            Content::Player {
+                position: _,
+                quaternion: _,
+                animation_blend_weights: _,
+                act: _,
+                subact: _,
+            }
+            | Content::Cap {
+                position: _,
+                quaternion: _,
+                cap_out: _,
+                cap_anim: _,
+            } => self
+                .hidden
+                .read()
+                .await
+                .iter()
+                .filter(|(target, _)| *target == packet.id)
+                .map(|(_, observer)| *observer)
+                .collect(),
+            _ => HashSet::new(),
         }
     }
 
     pub async fn broadcast(&self, packet: Packet) {
+        self.broadcast_with(packet, false).await;
+    }
+
+    // Packets are not sent back to the player with the packet id, unless include_sender is true
+    pub async fn broadcast_with(&self, packet: Packet, include_sender: bool) {
+        if self.is_relay_blocked(&packet).await {
+            return;
+        }
+
+        self.metrics.broadcast();
+
+        let hidden_from = self.hidden_from(&packet).await;
         let peers = self.peers.read().await;
 
         join_all(
             peers
                 .iter()
-                .filter(|(_, p)| p.connected && p.id != packet.id)
+                .filter(|(_, p)| p.connected && (include_sender || p.id != packet.id))
+                .filter(|(_, p)| !hidden_from.contains(&p.id))
                 .map(|(_, p)| p.send(packet.clone())),
         )
         .await;
     }
 
+    // Same as broadcast, but returns the number of players the packet was sent to
+    pub async fn broadcast_counted(&self, packet: Packet) -> usize {
+        if self.is_relay_blocked(&packet).await {
+            return 0;
+        }
+
+        self.metrics.broadcast();
+
+        let hidden_from = self.hidden_from(&packet).await;
+        let peers = self.peers.read().await;
+
+        join_all(
+            peers
+                .iter()
+                .filter(|(_, p)| p.connected && p.id != packet.id)
+                .filter(|(_, p)| !hidden_from.contains(&p.id))
+                .map(|(_, p)| p.try_send(packet.clone())),
+        )
+        .await
+        .into_iter()
+        .filter(Result::is_ok)
+        .count()
+    }
+
     pub async fn broadcast_map<F, Fut>(&self, packet: Packet, map: F)
     where
         F: Fn(SharedPlayer, Packet) -> Fut,
         Fut: Future<Output = Option<Packet>>,
     {
+        if self.is_relay_blocked(&packet).await {
+            return;
+        }
+
+        self.metrics.broadcast();
+
+        let hidden_from = self.hidden_from(&packet).await;
         let peers = self.peers.read().await;
 
         join_all(
             peers
                 .iter()
                 .filter(|(_, p)| p.connected && p.id != packet.id)
+                .filter(|(_, p)| !hidden_from.contains(&p.id))
                 .map(|(_, peer)| async {
+                    // Spectators receive everything, whatever their stage
                     let packet = match self.players.get(&peer.id).await {
+                        Some(_) if peer.spectator => Some(packet.clone()),
                         Some(p) => (map)(p, packet.clone()).await,
                         None => Some(packet.clone()),
                     };
@@ -75,6 +228,19 @@ impl Server {
         .await;
     }
 
+    pub async fn broadcast_to_stage(&self, stage: &str, packet: Packet) {
+        self.broadcast_map(packet, |player, packet| async move {
+            let player = player.read().await;
+
+            if player.get_stage().as_deref() == Some(stage) {
+                Some(packet)
+            } else {
+                None
+            }
+        })
+        .await;
+    }
+
     pub async fn send_to(&self, id: &Uuid, packet: Packet) -> Result<()> {
         let peers = self.peers.read().await;
 
@@ -96,28 +262,71 @@ impl Server {
             .collect()
     }
 
+    // Number of connected players whose last game packet was in the stage
+    pub async fn stage_occupancy(&self, stage: &str) -> usize {
+        let connected = self.connected_peers().await;
+        let players = self.players.all_from_ids(connected).await;
+
+        let players = join_all(players.iter().map(|p| p.read())).await;
+
+        players
+            .iter()
+            .filter(|player| player.get_stage().as_deref() == Some(stage))
+            .count()
+    }
+
     pub async fn handle_connection(self: Arc<Self>, socket: TcpStream) -> Result<()> {
         let mut id = Uuid::nil();
+        let mut packets: u64 = 0;
+        let ip = socket.peer_addr()?.ip();
+        // Tags every log of the connection, including the ones of the tasks it spawns. The fields
+        // are appended each time they're recorded, so packets is only recorded when it closes
+        let span = info_span!("connection", %ip, id = Empty, packets = Empty);
 
         let run = || async {
-            let ip = socket.peer_addr()?.ip();
             debug!(%ip, "Accepted incoming connection");
 
             let (mut reader, writer) = split(socket);
 
-            let mut peer = Peer::new(ip, writer);
+            let mut peer = Peer::new(ip, writer, Span::current());
+            let settings = self.settings_snapshot().await;
+            // Bounds the tasks spawned while handling packets so a fast client can't spawn them
+            // faster than they complete
+            let tasks = Arc::new(Semaphore::new(settings.max_tasks_per_connection.max(1)));
 
             peer.send(Packet::new(
                 peer.id,
                 Content::Init {
-                    max_player: self.settings.read().await.server.max_players,
+                    max_player: settings.max_players,
                 },
             ))
             .await;
 
-            let connect_packet = receive_packet(&mut reader).await?;
+            let connect_packet =
+                match receive_frame(&mut reader, settings.read_timeout, settings.max_packet_size)
+                    .await
+                {
+                    Ok(Some((header, body))) => {
+                        if settings.record {
+                            self.record(&header, &body).await;
+                        }
+
+                        header.make_packet(body)
+                    }
+                    Ok(None) => Ok(Packet::new(Uuid::nil(), Content::Disconnect)),
+                    Err(e) => Err(e),
+                };
+
+            let connect_packet = match connect_packet {
+                Ok(packet) => packet,
+                Err(e) => {
+                    self.metrics.reject(Rejection::BadHandshake);
+                    return Err(e);
+                }
+            };
 
             if !connect_packet.content.is_connect() {
+                self.metrics.reject(Rejection::BadHandshake);
                 debug!(
                     "Player {} didn't send connection packet on first connection",
                     connect_packet.id
@@ -125,15 +334,82 @@ impl Server {
                 return Err(eyre!("Didn't receive connection packet as first packet"));
             }
 
+            if let Some(remaining) = self.kick_cooldown(&connect_packet.id).await {
+                self.metrics.reject(Rejection::Kicked);
+                info!(
+                    "Kicked player {} tried to join, {}s left",
+                    connect_packet.id,
+                    remaining.as_secs()
+                );
+                return Err(eyre!("Kicked"));
+            }
+
+            peer.spectator = self
+                .settings
+                .read()
+                .await
+                .spectators
+                .ids
+                .contains(&connect_packet.id);
+
+            // Pending rules can ban the player, so they're applied before the checks
+            if let Content::Connect { client, .. } = &connect_packet.content {
+                if self.players.get(&connect_packet.id).await.is_none() {
+                    self.apply_pending_rules(connect_packet.id, client, peer.ip)
+                        .await;
+                }
+            }
+
+            self.check_admission(connect_packet.id, peer.ip).await?;
+
             let peers = self.peers.read().await;
 
-            let connected_peers = peers
-                .iter()
-                .fold(0, |acc, p| if p.1.connected { acc + 1 } else { 0 });
+            // The previous connection of a reconnecting player doesn't count
+            let connections_from_ip = peers
+                .values()
+                .filter(|p| p.connected && p.ip == peer.ip && p.id != connect_packet.id)
+                .count();
+
+            if settings.max_connections_per_ip > 0
+                && connections_from_ip >= settings.max_connections_per_ip
+            {
+                self.metrics.reject(Rejection::TooManyConnections);
+                info!(
+                    "Player {} couldn't join: {} already has {} connections",
+                    connect_packet.id, peer.ip, connections_from_ip
+                );
+                peer.disconnect().await;
+                return Err(eyre!("Too many connections"));
+            }
 
-            if connected_peers == self.settings.read().await.server.max_players {
-                info!("Player {} couldn't join: server is full", connect_packet.id);
-                return Err(eyre!("Server full"));
+            // Last, so nobody is evicted for a player that would be rejected anyway
+            let connected_peers = peers
+                .values()
+                .filter(|p| p.connected && !p.spectator)
+                .count();
+
+            if !peer.spectator
+                && connected_peers >= usize::try_from(settings.max_players).unwrap_or(0)
+            {
+                let least_active = peers
+                    .values()
+                    .filter(|p| p.connected && !p.spectator && p.id != connect_packet.id)
+                    .min_by_key(|p| p.last_activity());
+
+                match least_active {
+                    Some(peer) if settings.evict_when_full => {
+                        info!(
+                            "Server is full, disconnecting {} to let {} join",
+                            peer.id, connect_packet.id
+                        );
+                        peer.disconnect().await;
+                    }
+                    _ => {
+                        self.metrics.reject(Rejection::ServerFull);
+                        info!("Player {} couldn't join: server is full", connect_packet.id);
+                        return Err(eyre!("Server full"));
+                    }
+                }
             }
 
             drop(peers);
@@ -146,18 +422,21 @@ impl Server {
             }
 
             let content = connect_packet.content.clone();
-            match (content, self.players.get(&connect_packet.id).await) {
+            let is_reconnecting = match (content, self.players.get(&connect_packet.id).await) {
                 // Player already exist so reconnecting
                 (_, Some(player)) => {
                     let player = player.read().await;
 
                     peer.id = connect_packet.id;
 
-                    let peer = self.on_new_peer(peer).await?;
+                    self.on_new_peer(&peer).await;
 
                     id = connect_packet.id;
                     peers.insert(connect_packet.id, peer);
+                    self.players.see(id, player.name.clone()).await;
                     info!("[{}] {} reconnected", player.name, id);
+
+                    true
                 }
                 // Player doesn't exist so we create it
                 (
@@ -172,47 +451,94 @@ impl Server {
                     peer.id = connect_packet.id;
                     id = connect_packet.id;
 
-                    let _ = self
-                        .players
-                        .add(Player::new(connect_packet.id, client))
-                        .await;
+                    self.players.see(id, client.clone()).await;
+
+                    let mut player = Player::new(connect_packet.id, client);
+                    player.costume = self.players.known_costume(&id).await;
+
+                    let _ = self.players.add(player).await;
 
-                    let peer = self.on_new_peer(peer).await?;
+                    self.on_new_peer(&peer).await;
 
                     peers.insert(connect_packet.id, peer);
+
+                    false
                 }
                 _ => {
                     debug!("This case isn't supposed to be reach");
                     return Err(eyre!("This case isn't supposed to be reach"));
                 }
+            };
+
+            Span::current().record("id", display(id));
+
+            let costume = match self.players.get(&id).await {
+                Some(player) => player.read().await.costume.clone(),
+                None => None,
+            };
+
+            let mut is_spectator = peers.get(&id).is_some_and(|p| p.spectator);
+
+            if is_spectator {
+                info!("{} is spectating", id);
             }
 
-            tokio::spawn({
+            spawn_in_span({
                 let server = self.clone();
 
                 async move {
+                    if is_spectator {
+                        return;
+                    }
+
                     server.broadcast(connect_packet).await;
+
+                    // Otherwise the player appears with the default costume until they send one
+                    if let Some(costume) = costume {
+                        server
+                            .broadcast(Packet::new(
+                                id,
+                                Content::Costume {
+                                    body: costume.body,
+                                    cap: costume.cap,
+                                },
+                            ))
+                            .await;
+                    }
                 }
             });
 
             drop(peers);
 
+            if let Some(player) = self.players.get(&id).await {
+                let name = player.read().await.name.clone();
+
+                self.emit_event(EventKind::Connect, id, &name, ip).await;
+            }
+
             let peers = self.peers.read().await;
 
             let peer = peers
                 .get(&id)
                 .ok_or_else(|| eyre!("Peer is supposed to be in the HashMap"))?;
 
-            for (uuid, other_peer) in self.peers.read().await.iter() {
-                if *uuid == id || !other_peer.connected {
+            for (uuid, other_peer) in peers.iter() {
+                if *uuid == id || !other_peer.connected || other_peer.spectator {
                     continue;
                 }
 
-                let player = self
-                    .players
-                    .get(uuid)
-                    .await
-                    .expect("Peers and Players are desynchronized");
+                // Only the peer without a player is dropped, the new one can still join
+                let player = match self.players.get(uuid).await {
+                    Some(player) => player,
+                    None => {
+                        warn!(
+                            "Peers and Players are desynchronized, disconnecting {}",
+                            uuid
+                        );
+                        other_peer.disconnect().await;
+                        continue;
+                    }
+                };
 
                 let player = player.read().await;
 
@@ -220,7 +546,7 @@ impl Server {
                     player.id,
                     Content::Connect {
                         type_: ConnectionType::First,
-                        max_player: self.settings.read().await.server.max_players as u16,
+                        max_player: settings.max_players as u16,
                         client: player.name.clone(),
                     },
                 ))
@@ -242,14 +568,87 @@ impl Server {
 
             drop(peers);
 
-            let player = self
-                .players
-                .get(&id)
-                .await
-                .expect("Player is supposed to be here");
+            // The moons collected while the player was gone weren't sent to them
+            if is_reconnecting {
+                spawn_in_span({
+                    let server = self.clone();
+
+                    async move {
+                        let _ = server.sync_player_shine_bag(id).await;
+                    }
+                });
+            }
+
+            let player = self.players.get(&id).await.ok_or_else(|| {
+                warn!("Peers and Players are desynchronized, disconnecting {}", id);
+                eyre!("Player {} is supposed to be here", id)
+            })?;
+
+            // Only logged once per type to not flood the logs
+            let mut unknown_types = HashSet::new();
+            let mut malformed_packets = 0;
+            let mut rate_limiter = RateLimiter::new(settings.max_packets_per_second);
 
             loop {
-                let packet = receive_packet(&mut reader).await?;
+                let packet = match receive_frame(
+                    &mut reader,
+                    settings.read_timeout,
+                    settings.max_packet_size,
+                )
+                .await?
+                {
+                    Some((header, body)) => {
+                        packets += 1;
+
+                        // Before parsing, so the malformed packets are recorded too
+                        if settings.record {
+                            self.record(&header, &body).await;
+                        }
+
+                        if !rate_limiter.allow() {
+                            info!(
+                                "Disconnected {}: more than {} packets per second",
+                                id, settings.max_packets_per_second
+                            );
+
+                            return Err(eyre!("Too many packets"));
+                        }
+
+                        if let Some(peer) = self.peers.read().await.get(&id) {
+                            peer.touch();
+                            is_spectator = peer.spectator;
+
+                            match Protocol::infer(header.type_, body.len()) {
+                                Some(protocol) if peer.set_protocol(protocol) => {
+                                    info!("{} is using the {:?} protocol", id, protocol);
+                                }
+                                _ => (),
+                            }
+                        }
+
+                        match header.make_packet(body) {
+                            Ok(packet) => packet,
+                            Err(e) => {
+                                malformed_packets += 1;
+                                debug!(
+                                    "Malformed packet of type {} from {}: {}",
+                                    header.type_, id, e
+                                );
+
+                                if malformed_packets > settings.max_malformed_packets {
+                                    self.on_malformed_packets(id).await;
+
+                                    return Err(eyre!("Too many malformed packets"));
+                                }
+
+                                continue;
+                            }
+                        }
+                    }
+                    None => Packet::new(Uuid::nil(), Content::Disconnect),
+                };
+
+                self.metrics.receive(&packet.content);
 
                 if packet.content.is_disconnect() {
                     break;
@@ -263,14 +662,101 @@ impl Server {
                     ));
                 }
 
+                // The others don't know the spectator exists, so nothing it sends is relayed
+                if is_spectator {
+                    continue;
+                }
+
+                let settings = self.settings_snapshot().await;
+
+                // Until the player sent its stage, its movement would be shown in every stage
+                let is_loading = match &packet.content {
+                    Content::Player {
+                        position: _,
+                        quaternion: _,
+                        animation_blend_weights: _,
+                        act: _,
+                        subact: _,
+                    }
+                    | Content::Cap {
+                        position: _,
+                        quaternion: _,
+                        cap_out: _,
+                        cap_anim: _,
+                    } => player.read().await.last_game_packet.is_none(),
+                    _ => false,
+                };
+
+                // Frozen players appear stationary, their movement is replaced before anything
+                // else handles it
+                let packet = match &packet.content {
+                    Content::Player {
+                        position: _,
+                        quaternion: _,
+                        animation_blend_weights: _,
+                        act: _,
+                        subact: _,
+                    } => match &player.read().await.frozen_position {
+                        Some(frozen) => Packet::new(packet.id, frozen.clone()),
+                        None => packet,
+                    },
+                    _ => packet,
+                };
+
+                // Trades latency for smoothness, see server.position_smoothing
+                let packet = match &packet.content {
+                    Content::Player {
+                        position,
+                        quaternion,
+                        animation_blend_weights,
+                        act,
+                        subact,
+                    } if settings.position_smoothing > 0. && !is_loading => {
+                        let mut player = player.write().await;
+                        let position = smooth_position(
+                            player.smoothed_position,
+                            *position,
+                            settings.position_smoothing,
+                        );
+                        player.smoothed_position = Some(position);
+
+                        Packet::new(
+                            packet.id,
+                            Content::Player {
+                                position,
+                                quaternion: *quaternion,
+                                animation_blend_weights: animation_blend_weights.clone(),
+                                act: *act,
+                                subact: *subact,
+                            },
+                        )
+                    }
+                    _ => packet,
+                };
+
+                // The flip settings are only needed for the movement, they aren't in the snapshot
+                let (flip_in, flip_not_in) = match &packet.content {
+                    Content::Player {
+                        position: _,
+                        quaternion: _,
+                        animation_blend_weights: _,
+                        act: _,
+                        subact: _,
+                    } => {
+                        let flip = &self.settings.read().await.flip;
+
+                        (flip.flip_in(&packet.id), flip.flip_not_in(&packet.id))
+                    }
+                    _ => (false, false),
+                };
+
                 let should_broadcast = match &packet.content {
                     Content::Costume { body, cap } => {
                         let mut player = player.write().await;
 
-                        player.set_costume(body.clone(), cap.clone());
                         player.loaded_save = true;
 
-                        tokio::spawn({
+                        spawn_in_span({
                             let server = self.clone();
                             let id = player.id;
 
@@ -279,13 +765,18 @@ impl Server {
                             }
                         });
 
-                        let settings = self.settings.read().await;
+                        let costume_settings = self.settings.read().await;
 
-                        let is_allowed_special = settings.special_costume_allowed(&player.id);
-                        let is_cap_special = settings.is_special_costume(cap);
-                        let is_body_special = settings.is_special_costume(body);
+                        let is_allowed_special =
+                            costume_settings.special_costume_allowed(&player.id);
+                        let is_cap_special = costume_settings.is_special_costume(cap);
+                        let is_body_special = costume_settings.is_special_costume(body);
+                        let filter = &costume_settings.costume_filter;
+                        let filtered = filter
+                            .is_filtered(&player.id)
+                            .then(|| (filter.body.clone(), filter.cap.clone()));
 
-                        drop(settings);
+                        drop(costume_settings);
                         let fallback = "Mario".to_owned();
 
                         let cap = match (is_cap_special, is_allowed_special) {
@@ -298,12 +789,36 @@ impl Server {
                             _ => body.clone(),
                         };
 
+                        let (body, cap) = filtered.unwrap_or((body, cap));
+
+                        // The stored costume is sent to the players joining later and on resync,
+                        // so it's the one without the special costumes
+                        player.set_costume(body.clone(), cap.clone());
+
+                        self.players
+                            .remember_costume(
+                                packet.id,
+                                Costume {
+                                    body: body.clone(),
+                                    cap: cap.clone(),
+                                },
+                            )
+                            .await;
+
                         let outgoing = Packet {
                             id: packet.id,
                             content: Content::Costume { body, cap },
                         };
 
-                        self.broadcast(outgoing).await;
+                        let sender_stage = player.get_stage();
+                        drop(player);
+
+                        match (settings.stage_scoping, sender_stage) {
+                            (false, _) => self.broadcast(outgoing).await,
+                            (true, Some(stage)) => self.broadcast_to_stage(&stage, outgoing).await,
+                            // It will be sent to the players of the stage they enter
+                            (true, None) => (),
+                        }
 
                         false
                     }
@@ -313,26 +828,52 @@ impl Server {
                         stage: self_stage,
                     } => {
                         let mut player = player.write().await;
-                        info!("{}: {}->{}", player.name, self_stage, scenario);
+                        info!(
+                            "{}: {}->{}",
+                            player.name,
+                            Stage::display_raw(self_stage),
+                            scenario
+                        );
+
+                        let previous_stage = player.get_stage();
+
+                        if previous_stage.as_ref() != Some(self_stage) {
+                            player.last_cap = None;
+                            player.smoothed_position = None;
+                        }
 
                         player.scenario = Some(*scenario);
                         player.is_2d = *is_2d;
                         player.last_game_packet = Some(packet.clone());
 
+                        if settings.stage_scoping && previous_stage.as_ref() != Some(self_stage) {
+                            spawn_in_span({
+                                let server = self.clone();
+                                let stage = self_stage.clone();
+
+                                async move {
+                                    server.resync_costumes(id, &stage).await;
+                                }
+                            });
+                        }
+
                         if self_stage == "CapWorldHomeStage" && *scenario == 0 {
                             player.is_speedrun = true;
                             player.shine_sync.clear();
-                            let mut shine_bag = self.shine_bag.write().await;
 
-                            shine_bag.clear();
+                            if !settings.per_player_new_save {
+                                let mut shine_bag = self.shine_bag.write().await;
 
-                            tokio::spawn({
-                                let server = self.clone();
+                                shine_bag.clear();
 
-                                async move {
-                                    server.persist_shines().await;
-                                }
-                            });
+                                spawn_in_span({
+                                    let server = self.clone();
+
+                                    async move {
+                                        server.persist_shines().await;
+                                    }
+                                });
+                            }
 
                             info!("Entered Cap on new save, preventing moon sync until Cascade");
                         } else if self_stage == "WaterfallWorldHomeStage" {
@@ -342,7 +883,7 @@ impl Server {
                             if was_speedrun {
                                 let id = player.id;
 
-                                tokio::spawn({
+                                spawn_in_span({
                                     let server = self.clone();
                                     async move {
                                         info!("Entered Cascade with moon sync disabled, enabling moon sync");
@@ -355,24 +896,33 @@ impl Server {
 
                         drop(player);
 
-                        let should_broadcast = if self.settings.read().await.scenario.merge_enabled
-                        {
-                            tokio::spawn({
+                        let merge = self.settings.read().await.scenario.merge_in(self_stage);
+
+                        let should_broadcast = if merge {
+                            spawn_in_span({
                                 let server = self.clone();
+                                let permit = tasks.clone().acquire_owned().await?;
                                 let packet = packet.clone();
 
                                 async move {
+                                    let _permit = permit;
+
                                     server
                                         .broadcast_map(packet, |player, packet| async move {
                                             let packet = match packet.content {
                                                 Content::Game {
                                                     is_2d,
-                                                    scenario: _,
+                                                    scenario,
                                                     stage,
                                                 } => {
                                                     let player = player.read().await;
 
-                                                    let scenario = player.scenario.unwrap_or(200);
+                                                    // Until the receiver sent its scenario, the one of
+                                                    // the sender is kept
+                                                    let scenario = Content::clamp_scenario(
+                                                        player.scenario.unwrap_or(scenario),
+                                                    )
+                                                    .unsigned_abs();
                                                     Packet::new(
                                                         packet.id,
                                                         Content::Game {
@@ -407,11 +957,39 @@ impl Server {
                             let positions = join_all(players.iter().map(|p| async move {
                                 let player = p.read().await;
 
-                                (player.get_stage(), player.id, player.last_position.clone())
+                                (
+                                    player.get_stage(),
+                                    player.id,
+                                    player.last_position.clone(),
+                                    player.is_seeking,
+                                    player.last_position(),
+                                    player.last_cap.clone(),
+                                )
                             }))
                             .await;
 
-                            for (stage, id, position) in positions {
+                            let (receiver_seeking, receiver_position) = positions
+                                .iter()
+                                .find(|(_, player_id, _, _, _, _)| *player_id == id)
+                                .map_or((false, None), |(_, _, _, seeking, position, _)| {
+                                    (*seeking, *position)
+                                });
+
+                            for (stage, player_id, position, is_seeking, seeker_position, cap) in
+                                positions
+                            {
+                                if settings.hide_seekers
+                                    && is_seeking
+                                    && !receiver_seeking
+                                    && !is_seeker_revealed(
+                                        seeker_position,
+                                        receiver_position,
+                                        settings.reveal_radius,
+                                    )
+                                {
+                                    continue;
+                                }
+
                                 match (stage, &position) {
                                     (
                                         Some(player_stage),
@@ -423,7 +1001,11 @@ impl Server {
                                             subact: _,
                                         }),
                                     ) if &player_stage == self_stage => {
-                                        peer.send(Packet::new(id, position.unwrap())).await
+                                        peer.send(Packet::new(player_id, position.unwrap())).await;
+
+                                        if let Some(cap) = cap.filter(|_| player_id != id) {
+                                            peer.send(cap).await;
+                                        }
                                     }
                                     _ => (),
                                 }
@@ -459,21 +1041,21 @@ impl Server {
 
                         true
                     }
-                    Content::Shine { id } => {
+                    Content::Shine { id, is_grand } => {
                         let mut player = player.write().await;
 
                         if player.loaded_save {
                             let mut shine_bag = self.shine_bag.write().await;
 
-                            let shine = *id;
+                            let shine = (*id, *is_grand);
 
                             shine_bag.insert(shine);
 
-                            if player.shine_sync.get(&shine).is_none() {
+                            if !player.shine_sync.contains(&shine) {
                                 info!("Got moon {}", id);
                                 player.shine_sync.insert(shine);
 
-                                tokio::spawn({
+                                spawn_in_span({
                                     let server = self.clone();
                                     async move {
                                         server.sync_shine_bag().await;
@@ -485,46 +1067,76 @@ impl Server {
                         true
                     }
                     Content::Player {
-                        position: game_pos,
-                        quaternion,
-                        animation_blend_weights,
-                        act,
-                        subact,
-                    } if self.settings.read().await.flip_in(&packet.id) => {
+                        position: _,
+                        quaternion: _,
+                        animation_blend_weights: _,
+                        act: _,
+                        subact: _,
+                    } if is_loading => {
+                        let mut player = player.write().await;
+                        player.last_position = Some(packet.content.clone());
+                        player.loaded_save = true;
+
+                        false
+                    }
+                    Content::Cap {
+                        position: _,
+                        quaternion: _,
+                        cap_out: _,
+                        cap_anim: _,
+                    } if is_loading => false,
+                    Content::Cap {
+                        position: _,
+                        quaternion: _,
+                        cap_out: _,
+                        cap_anim: _,
+                    } => {
+                        let mut player = player.write().await;
+                        player.last_cap = Some(packet.clone());
+                        let sender_stage = player.get_stage();
+                        drop(player);
+
+                        match (settings.stage_scoping, sender_stage) {
+                            (true, Some(stage)) => {
+                                self.broadcast_to_stage(&stage, packet.clone()).await;
+
+                                false
+                            }
+                            _ => true,
+                        }
+                    }
+                    Content::Player {
+                        position: _,
+                        quaternion: _,
+                        animation_blend_weights: _,
+                        act: _,
+                        subact: _,
+                    } if flip_in => {
                         let mut player = player.write().await;
                         player.last_position = Some(packet.content.clone());
                         player.loaded_save = true;
                         let size = player.size();
                         let sender_stage = player.get_stage();
+                        let hide_seeker = settings.hide_seekers && player.is_seeking;
+                        let sender_position = player.last_position();
+                        let reveal_radius = settings.reveal_radius;
 
                         drop(player);
 
-                        tokio::spawn({
+                        spawn_in_span({
                             let server = self.clone();
+                            let permit = tasks.clone().acquire_owned().await?;
 
-                            let id = packet.id;
-                            let position = *game_pos;
-                            let quaternion = *quaternion;
-                            let animation_blend_weights = animation_blend_weights.clone();
-                            let act = *act;
-                            let subact = *subact;
-
-                            let position = position + Vec3::Y * size;
-                            let quaternion = quaternion
-                                * Quat::from_mat4(&Mat4::from_rotation_x(std::f32::consts::PI))
-                                * Quat::from_mat4(&Mat4::from_rotation_y(std::f32::consts::PI));
+                            let packet = match packet.content.flipped(size) {
+                                Some(content) => Packet::new(packet.id, content),
+                                None => {
+                                    debug!("Couldn't flip the position of {}", packet.id);
+                                    packet.clone()
+                                }
+                            };
 
                             async move {
-                                let packet = Packet::new(
-                                    id,
-                                    Content::Player {
-                                        position,
-                                        quaternion,
-                                        animation_blend_weights,
-                                        act,
-                                        subact,
-                                    },
-                                );
+                                let _permit = permit;
 
                                 server
                                     .broadcast_map(packet.clone(), |player, packet| {
@@ -534,12 +1146,19 @@ impl Server {
                                             let player = player.read().await;
 
                                             let receiver_stage = player.get_stage();
+                                            let hidden = hide_seeker
+                                                && !player.is_seeking
+                                                && !is_seeker_revealed(
+                                                    sender_position,
+                                                    player.last_position(),
+                                                    reveal_radius,
+                                                );
 
                                             drop(player);
 
                                             match (sender_stage.clone(), receiver_stage) {
                                                 (Some(sender), Some(receiver))
-                                                    if sender == receiver =>
+                                                    if sender == receiver && !hidden =>
                                                 {
                                                     Some(packet)
                                                 }
@@ -560,19 +1179,25 @@ impl Server {
                         animation_blend_weights: _,
                         act: _,
                         subact: _,
-                    } if self.settings.read().await.flip_not_in(&packet.id) => {
+                    } if flip_not_in => {
                         let mut player = player.write().await;
                         player.last_position = Some(packet.content.clone());
                         player.loaded_save = true;
                         let sender_stage = player.get_stage();
+                        let hide_seeker = settings.hide_seekers && player.is_seeking;
+                        let sender_position = player.last_position();
+                        let reveal_radius = settings.reveal_radius;
                         drop(player);
 
-                        tokio::spawn({
+                        spawn_in_span({
                             let server = self.clone();
+                            let permit = tasks.clone().acquire_owned().await?;
 
                             let packet = packet.clone();
 
                             async move {
+                                let _permit = permit;
+
                                 server
                                     .broadcast_map(packet, |player, packet| {
                                         let sender_stage = sender_stage.clone();
@@ -581,39 +1206,31 @@ impl Server {
                                             let player = player.read().await;
                                             let receiver_stage = player.get_stage();
                                             let size = player.size();
+                                            let hidden = hide_seeker
+                                                && !player.is_seeking
+                                                && !is_seeker_revealed(
+                                                    sender_position,
+                                                    player.last_position(),
+                                                    reveal_radius,
+                                                );
                                             drop(player);
 
-                                            match (sender_stage, receiver_stage, packet.content) {
-                                                (
-                                                    Some(sender),
-                                                    Some(receiver),
-                                                    Content::Player {
-                                                        position,
-                                                        quaternion,
-                                                        animation_blend_weights,
-                                                        act,
-                                                        subact,
-                                                    },
-                                                ) if sender == receiver => {
-                                                    let position = position + Vec3::Y * size;
-                                                    let quaternion = quaternion
-                                                        * Quat::from_mat4(&Mat4::from_rotation_x(
-                                                            std::f32::consts::PI,
-                                                        ))
-                                                        * Quat::from_mat4(&Mat4::from_rotation_y(
-                                                            std::f32::consts::PI,
-                                                        ));
-
-                                                    Some(Packet::new(
-                                                        id,
-                                                        Content::Player {
-                                                            position,
-                                                            quaternion,
-                                                            animation_blend_weights,
-                                                            act,
-                                                            subact,
-                                                        },
-                                                    ))
+                                            match (sender_stage, receiver_stage) {
+                                                (Some(sender), Some(receiver))
+                                                    if sender == receiver && !hidden =>
+                                                {
+                                                    match packet.content.flipped(size) {
+                                                        Some(content) => {
+                                                            Some(Packet::new(id, content))
+                                                        }
+                                                        None => {
+                                                            debug!(
+                                                                "Couldn't flip the position of {}",
+                                                                id
+                                                            );
+                                                            Some(packet)
+                                                        }
+                                                    }
                                                 }
                                                 _ => None,
                                             }
@@ -636,14 +1253,20 @@ impl Server {
                         player.last_position = Some(packet.content.clone());
                         player.loaded_save = true;
                         let sender_stage = player.get_stage();
+                        let hide_seeker = settings.hide_seekers && player.is_seeking;
+                        let sender_position = player.last_position();
+                        let reveal_radius = settings.reveal_radius;
                         drop(player);
 
-                        tokio::spawn({
+                        spawn_in_span({
                             let server = self.clone();
+                            let permit = tasks.clone().acquire_owned().await?;
 
                             let packet = packet.clone();
 
                             async move {
+                                let _permit = permit;
+
                                 server
                                     .broadcast_map(packet, |player, packet| {
                                         let sender_stage = sender_stage.clone();
@@ -651,11 +1274,18 @@ impl Server {
                                         async move {
                                             let player = player.read().await;
                                             let receiver_stage = player.get_stage();
+                                            let hidden = hide_seeker
+                                                && !player.is_seeking
+                                                && !is_seeker_revealed(
+                                                    sender_position,
+                                                    player.last_position(),
+                                                    reveal_radius,
+                                                );
                                             drop(player);
 
                                             match (sender_stage, receiver_stage) {
                                                 (Some(sender), Some(receiver))
-                                                    if sender == receiver =>
+                                                    if sender == receiver && !hidden =>
                                                 {
                                                     Some(packet)
                                                 }
@@ -669,30 +1299,400 @@ impl Server {
 
                         false
                     }
-                    Content::Unknown => false,
+                    Content::Unknown { type_id } => {
+                        if unknown_types.insert(*type_id) {
+                            warn!(
+                                "{} sent a packet with an unknown type {}, it won't be relayed",
+                                id, type_id
+                            );
+                        }
+
+                        false
+                    }
+                    Content::Capture { model } => {
+                        let mut player = player.write().await;
+
+                        player.captured = Some(model.clone()).filter(|model| !model.is_empty());
+
+                        true
+                    }
+                    // Messages come from the server, a client sending type 12 means something
+                    // else by it, see Content::ChatMessage
+                    Content::ChatMessage { message: _ } => false,
                     _ => true,
                 };
 
+                if settings.proximity_radius > 0. && packet.content.type_name() == "player" {
+                    self.check_proximity(id, settings.proximity_radius).await;
+                }
+
                 if should_broadcast {
                     self.broadcast(packet).await;
                 }
             }
 
-            // TODO: Find out when peers & players are cleaned
+            Span::current().record("packets", packets);
             self.disconnect(id).await;
+            self.schedule_cleanup(id).await;
 
             Ok(())
         };
 
-        match run().await {
+        match run().instrument(span.clone()).await {
             Ok(_) => Ok(()),
             Err(e) => {
-                self.disconnect(id).await;
+                span.record("packets", packets);
+
+                async {
+                    self.disconnect(id).await;
+                    self.schedule_cleanup(id).await;
+                }
+                .instrument(span)
+                .await;
+
                 Err(e)
             }
         }
     }
 
+    async fn schedule_cleanup(self: &Arc<Self>, id: Uuid) {
+        let grace =
+            std::time::Duration::from_secs(self.settings.read().await.server.player_cleanup_secs);
+
+        if grace.is_zero() || id.is_nil() {
+            return;
+        }
+
+        spawn_in_span({
+            let server = self.clone();
+
+            async move {
+                sleep(grace).await;
+                server.cleanup(id, grace).await;
+            }
+        });
+    }
+
+    // Removes the peer and the player if it didn't reconnect since at least grace. Returns true
+    // if the player was removed
+    async fn cleanup(&self, id: Uuid, grace: std::time::Duration) -> bool {
+        // Kept locked until the player is removed, so it can't reconnect in between
+        let mut peers = self.peers.write().await;
+
+        // A player that disconnected again after reconnecting is handled by the latest cleanup
+        let is_gone = peers
+            .get(&id)
+            .is_none_or(|p| !p.connected && p.last_activity().elapsed() >= grace);
+
+        if !is_gone {
+            return false;
+        }
+
+        peers.remove(&id);
+        let removed = self.players.remove(&id).await.is_some();
+        drop(peers);
+
+        if removed {
+            debug!(
+                "Removed {}, it didn't reconnect for {}s",
+                id,
+                grace.as_secs()
+            );
+        }
+
+        removed
+    }
+
+    pub async fn set_broadcasting_paused(&self, paused: bool) {
+        let was_paused = self.broadcasting_paused.swap(paused, Ordering::Relaxed);
+
+        if was_paused && !paused {
+            self.resync(None).await;
+        }
+    }
+
+    // Sends the last known stage, costume and position of every player (or only the given one) to
+    // the others, so they catch up on what happened while broadcasting was paused
+    pub async fn resync(&self, only: Option<Uuid>) {
+        struct State {
+            id: Uuid,
+            stage: Option<String>,
+            size: f32,
+            is_seeking: bool,
+            position: Option<Vec3>,
+            game: Option<Packet>,
+            costume: Option<Packet>,
+            last_position: Option<Content>,
+        }
+
+        let settings = self.settings_snapshot().await;
+        let flip = self.settings.read().await.flip.clone();
+        let players = self.players.all().await;
+
+        let states = join_all(players.iter().map(|p| async move {
+            let player = p.read().await;
+
+            State {
+                id: player.id,
+                stage: player.get_stage(),
+                size: player.size(),
+                is_seeking: player.is_seeking,
+                position: player.last_position(),
+                game: player.last_game_packet.clone(),
+                costume: player.costume.clone().map(|costume| {
+                    Packet::new(
+                        player.id,
+                        Content::Costume {
+                            body: costume.body,
+                            cap: costume.cap,
+                        },
+                    )
+                }),
+                last_position: player.last_position.clone(),
+            }
+        }))
+        .await;
+
+        let peers = self.peers.read().await;
+
+        for peer in peers.values().filter(|p| p.connected) {
+            let receiver = match states.iter().find(|state| state.id == peer.id) {
+                Some(receiver) => receiver,
+                None => continue,
+            };
+
+            for state in states
+                .iter()
+                .filter(|state| state.id != peer.id && only.is_none_or(|id| id == state.id))
+            {
+                let same_stage = state.stage.is_some() && state.stage == receiver.stage;
+
+                if let Some(game) = &state.game {
+                    peer.send(game.clone()).await;
+                }
+
+                match &state.costume {
+                    Some(costume) if !settings.stage_scoping || same_stage => {
+                        peer.send(costume.clone()).await
+                    }
+                    _ => (),
+                }
+
+                let hidden = settings.hide_seekers
+                    && state.is_seeking
+                    && !receiver.is_seeking
+                    && !is_seeker_revealed(
+                        state.position,
+                        receiver.position,
+                        settings.reveal_radius,
+                    );
+
+                // Same rules as when the position is received
+                let flip_size = if flip.flip_in(&state.id) {
+                    Some(state.size)
+                } else if flip.flip_not_in(&state.id) {
+                    Some(receiver.size)
+                } else {
+                    None
+                };
+
+                match &state.last_position {
+                    Some(position) if same_stage && !hidden => {
+                        let position = flip_size
+                            .and_then(|size| position.flipped(size))
+                            .unwrap_or_else(|| position.clone());
+
+                        peer.send(Packet::new(state.id, position)).await
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // Swaps the settings and applies the changes that would otherwise only be noticed on the
+    // next connection or packet
+    // Refuses settings with errors, see Settings::validate
+    pub async fn apply_settings(&self, updated: Settings) -> Result<(), String> {
+        updated.check()?;
+
+        let ban_list = updated.ban_list.clone();
+        let allow_list = updated.allow_list.clone();
+
+        // Released before locking peers, handle_connection locks them in the other order
+        let needs_resync = {
+            let mut settings = self.settings.write().await;
+
+            let needs_resync = settings.flip != updated.flip
+                || settings.server.stage_scoping != updated.server.stage_scoping
+                || settings.tag != updated.tag;
+
+            *settings = updated;
+
+            needs_resync
+        };
+
+        let peers = self.peers.read().await;
+
+        for peer in peers.values().filter(|p| p.connected) {
+            if ban_list.ids.contains(&peer.id) || ban_list.is_ip_ban(&peer.ip) {
+                info!("Disconnected banned player {}", peer.id);
+                peer.disconnect().await;
+            } else if !allow_list.is_allowed(&peer.id) {
+                info!("Disconnected player {} not in the allow list", peer.id);
+                peer.disconnect().await;
+            }
+        }
+
+        drop(peers);
+
+        if needs_resync {
+            self.resync(None).await;
+        }
+
+        Ok(())
+    }
+
+    // Only logs when a seeker gets near a hider, so someone can decide if the hider was caught
+    async fn check_proximity(&self, id: Uuid, radius: f32) {
+        let players = self.players.all().await;
+
+        let states = join_all(players.iter().map(|p| async move {
+            let player = p.read().await;
+
+            (
+                player.id,
+                player.name.clone(),
+                player.get_stage(),
+                player.is_seeking,
+                player.last_position(),
+            )
+        }))
+        .await;
+
+        let (name, stage, is_seeking, position) = match states.iter().find(|state| state.0 == id) {
+            Some((_, name, Some(stage), is_seeking, Some(position))) => {
+                (name, stage, *is_seeking, *position)
+            }
+            _ => return,
+        };
+
+        let mut near_players = self.near_players.write().await;
+
+        for (other_id, other_name, other_stage, other_seeking, other_position) in &states {
+            if *other_seeking == is_seeking {
+                continue;
+            }
+
+            let pair = if is_seeking {
+                (id, *other_id)
+            } else {
+                (*other_id, id)
+            };
+
+            let is_near = match (other_stage, other_position) {
+                (Some(other_stage), Some(other_position)) if other_stage == stage => {
+                    position.distance(*other_position) <= radius
+                }
+                _ => false,
+            };
+
+            if !is_near {
+                near_players.remove(&pair);
+            } else if near_players.insert(pair) {
+                if is_seeking {
+                    info!("Seeker {} is near hider {}", name, other_name);
+                } else {
+                    info!("Seeker {} is near hider {}", other_name, name);
+                }
+            }
+        }
+    }
+
+    async fn emit_event(&self, kind: EventKind, id: Uuid, name: &str, ip: IpAddr) {
+        let settings = self.settings.read().await;
+        let path = settings.events.jsonl_path.clone();
+        let webhook = Some(settings.webhook.url.clone()).filter(|_| settings.webhook.enabled);
+        drop(settings);
+
+        let event = Event::new(kind, id, name, ip);
+
+        if let Some(path) = path {
+            event.emit(&path).await;
+        }
+
+        if let Some(url) = webhook {
+            event.post(&self.webhook_client, url);
+        }
+    }
+
+    // Called before the command runs, so commands like stop are audited too
+    pub async fn audit(&self, source: &str, command: &str) {
+        let settings = self.settings.read().await;
+        let path =
+            Some(settings.audit_log.file_name.clone()).filter(|_| settings.audit_log.enabled);
+        drop(settings);
+
+        if let Some(path) = path {
+            AuditEntry::new(source, command).append(&path).await;
+        }
+    }
+
+    async fn record(&self, header: &Header, body: &Bytes) {
+        let path = self.settings.read().await.record.file_name.clone();
+
+        self.recorder.write(&path, header, body).await;
+    }
+
+    async fn on_malformed_packets(&self, id: Uuid) {
+        // Read before locking the settings, handle_connection locks peers first
+        let ip = self.peers.read().await.get(&id).map(|peer| peer.ip);
+
+        let mut settings = self.settings.write().await;
+
+        if !settings.ban_list.auto_ban_malformed {
+            info!("Disconnected {}: too many malformed packets", id);
+            return;
+        }
+
+        settings.ban_list.ban(id, ip);
+        settings.save().await;
+
+        info!("Banned {}: too many malformed packets", id);
+    }
+
+    // When costumes are scoped to stages, the player entering a stage and the players already in it
+    // never received each other costumes
+    async fn resync_costumes(&self, id: Uuid, stage: &str) {
+        let players = self.players.all().await;
+
+        let costumes = join_all(players.iter().map(|p| async move {
+            let player = p.read().await;
+
+            match (player.get_stage(), &player.costume) {
+                (Some(player_stage), Some(costume)) if player_stage == stage => Some(Packet::new(
+                    player.id,
+                    Content::Costume {
+                        body: costume.body.clone(),
+                        cap: costume.cap.clone(),
+                    },
+                )),
+                _ => None,
+            }
+        }))
+        .await;
+
+        let peers = self.peers.read().await;
+
+        for packet in costumes.into_iter().flatten() {
+            if packet.id == id {
+                self.broadcast_to_stage(stage, packet).await;
+            } else if let Some(peer) = peers.get(&id) {
+                peer.send(packet).await;
+            }
+        }
+    }
+
     async fn disconnect(&self, id: Uuid) {
         let mut peers = self.peers.write().await;
         let peer = peers.get_mut(&id);
@@ -701,50 +1701,64 @@ impl Server {
             return;
         }
 
-        let mut peer = peer.unwrap();
+        let peer = peer.unwrap();
+        peer.connected = false;
+        // The cleanup grace period starts now
+        peer.touch();
 
-        let player = self
-            .players
-            .get(&id)
-            .await
-            .expect("Player is supposed to be here");
+        let player = match self.players.get(&id).await {
+            Some(player) => player,
+            None => {
+                warn!("Peers and Players are desynchronized, disconnected {}", id);
+                peer.disconnect().await;
+                return;
+            }
+        };
 
         let player = player.read().await;
-        peer.connected = false;
         peer.disconnect().await;
+        let ip = peer.ip;
         drop(peers);
         self.broadcast(Packet::new(id, Content::Disconnect)).await;
+        self.players.see(id, player.name.clone()).await;
+        self.persist_players().await;
+        self.persist_costumes().await;
+        self.emit_event(EventKind::Disconnect, id, &player.name, ip)
+            .await;
 
         info!("{} just disconnected", player.name);
     }
 
-    async fn on_new_peer(&self, peer: Peer) -> Result<Peer> {
+    // Ban and allow list, checked before the server makes room for the player
+    async fn check_admission(&self, id: Uuid, ip: IpAddr) -> Result<()> {
         let settings = self.settings.read().await;
 
-        let is_ip_banned = settings.ban_list.ips.iter().any(|addr| *addr == peer.ip);
-        let is_id_banned = settings.ban_list.ids.iter().any(|addr| peer.id == *addr);
+        let is_ip_banned = settings.ban_list.is_ip_ban(&ip);
+        let is_id_banned = settings.ban_list.ids.contains(&id);
+        let is_allowed = settings.allow_list.is_allowed(&id);
 
         drop(settings);
 
         if is_id_banned || is_ip_banned {
-            info!(
-                "Banned player {} with ip {} tried to joined",
-                peer.ip, peer.id
-            );
+            self.metrics.reject(Rejection::Banned);
+            info!("Banned player {} with ip {} tried to joined", id, ip);
 
-            Err(eyre!(
-                "Banned player {} with ip {} tried to joined",
-                peer.ip,
-                peer.id
-            ))
+            Err(eyre!("Banned player {} with ip {} tried to joined", id, ip))
+        } else if !is_allowed {
+            self.metrics.reject(Rejection::NotAllowed);
+            info!("Player {} isn't in the allow list", id);
+
+            Err(eyre!("Player {} isn't in the allow list", id))
         } else {
-            let packets = self.players.get_last_game_packets().await;
+            Ok(())
+        }
+    }
 
-            for packet in packets {
-                peer.send(packet).await;
-            }
+    async fn on_new_peer(&self, peer: &Peer) {
+        let packets = self.players.get_last_game_packets().await;
 
-            Ok(peer)
+        for packet in packets {
+            peer.send(packet).await;
         }
     }
 
@@ -765,26 +1779,79 @@ impl Server {
         let peers = self.peers.read().await;
         let peer = peers.get(&id).ok_or_else(|| eyre!("Couldn't find peer"))?;
 
-        for shine_id in bag.difference(&player.shine_sync.clone()) {
-            player.shine_sync.insert(*shine_id);
+        // Otherwise the moons would be marked as synced without the player receiving them
+        if !peer.connected {
+            return Err(eyre!("Peer is disconnected"));
+        }
 
-            peer.send(Packet::new(id, Content::Shine { id: *shine_id }))
-                .await
+        for (shine_id, is_grand) in bag.difference(&player.shine_sync.clone()) {
+            player.shine_sync.insert((*shine_id, *is_grand));
+
+            peer.send(Packet::new(
+                id,
+                Content::Shine {
+                    id: *shine_id,
+                    is_grand: *is_grand,
+                },
+            ))
+            .await
         }
 
         Ok(())
     }
 
+    // Sends a single moon to everyone, even to the players it was already sent to
+    pub async fn push_shine(&self, shine_id: i32, is_grand: bool) -> usize {
+        let shine = (shine_id, is_grand);
+        let mut bag = self.shine_bag.write().await;
+
+        // The bag is a set of (id, is_grand), the previous flag of the moon would stay otherwise
+        bag.retain(|(id, _)| *id != shine_id);
+        bag.insert(shine);
+        drop(bag);
+
+        self.persist_shines().await;
+
+        let mut count = 0;
+
+        for player in self.players.all().await {
+            let mut player = player.write().await;
+
+            if player.is_speedrun {
+                continue;
+            }
+
+            // Locked after the player, like in sync_player_shine_bag
+            let peers = self.peers.read().await;
+
+            if let Some(peer) = peers.get(&player.id).filter(|peer| peer.connected) {
+                player.shine_sync.insert(shine);
+                peer.send(Packet::new(
+                    player.id,
+                    Content::Shine {
+                        id: shine.0,
+                        is_grand: shine.1,
+                    },
+                ))
+                .await;
+                count += 1;
+            }
+        }
+
+        count
+    }
+
     async fn persist_shines(&self) {
         let settings = self.settings.read().await;
-        if !settings.persist_shines.enabled {
+        if !settings.persist_shines.enabled || self.shine_persistence_failed.load(Ordering::Relaxed)
+        {
             return;
         }
 
         let shines = self.shine_bag.read().await;
 
         let shines = shines.clone();
-        let file_name = settings.persist_shines.file_name.clone();
+        let file_name = settings.persist_shines.active_file_name();
 
         drop(settings);
 
@@ -798,7 +1865,37 @@ impl Server {
             });
     }
 
+    // Returns the number of moons removed
+    pub async fn clear_shines(&self) -> usize {
+        let count = {
+            let mut bag = self.shine_bag.write().await;
+            let count = bag.len();
+            bag.clear();
+
+            count
+        };
+
+        for player in self.players.all().await {
+            player.write().await.shine_sync.clear();
+        }
+
+        self.persist_shines().await;
+
+        count
+    }
+
+    // Returns the number of moons written
+    pub async fn dump_shines(&self, path: &str) -> Result<usize> {
+        let shines = self.shine_bag.read().await.clone();
+        let serialized = serde_json::to_string(&shines)?;
+
+        tokio::fs::write(path, serialized).await?;
+
+        Ok(shines.len())
+    }
+
     pub async fn sync_shine_bag(&self) {
+        self.metrics.shine_sync();
         self.persist_shines().await;
         join_all(
             self.players
@@ -818,72 +1915,712 @@ impl Server {
             return Ok(());
         }
 
-        let mut file = OpenOptions::new()
+        let file_name = settings.persist_shines.active_file_name();
+
+        drop(settings);
+
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(&settings.persist_shines.file_name)
-            .await
-            .expect("Moons couldn't be loaded or created");
+            .truncate(false)
+            .open(&file_name)
+            .await;
+
+        // Losing the moons persistence is better than not starting the server
+        let mut file = match file {
+            Ok(file) => file,
+            Err(err) => {
+                tracing::error!(
+                    %err,
+                    "Moons couldn't be loaded or created from {}, moon persistence is disabled",
+                    file_name
+                );
+                self.shine_persistence_failed.store(true, Ordering::Relaxed);
+
+                return Err(err.into());
+            }
+        };
+
+        self.shine_persistence_failed
+            .store(false, Ordering::Relaxed);
 
         let mut content = String::from("");
         file.read_to_string(&mut content).await?;
 
-        let deserialized = serde_json::from_str(&content).unwrap_or_default();
+        // Files saved before grand moons were tracked only contain the ids
+        let deserialized = serde_json::from_str::<HashSet<(i32, bool)>>(&content)
+            .or_else(|_| {
+                serde_json::from_str::<HashSet<i32>>(&content)
+                    .map(|ids| ids.into_iter().map(|id| (id, false)).collect())
+            })
+            .unwrap_or_default();
 
         let mut shines = self.shine_bag.write().await;
 
-        info!("Moons loaded from {}", settings.persist_shines.file_name);
-
-        drop(settings);
+        info!("Moons loaded from {}", file_name);
 
         *shines = deserialized;
 
         Ok(())
     }
 
-    pub async fn disconnect_all(&self) {
-        let peers = self.peers.read().await;
+    // Saves the moons of the current profile before replacing them by the ones of `profile`
+    pub async fn switch_shine_profile(&self, profile: &str) -> Result<()> {
+        let settings = self.settings.read().await;
 
-        join_all(peers.iter().map(|(_, peer)| peer.disconnect())).await;
-    }
+        if !settings.persist_shines.enabled {
+            return Err(eyre!("Moon sync is disabled"));
+        }
 
-    pub async fn disconnect_by_name(&self, players: Vec<String>) {
-        let ids = join_all(
-            players
-                .into_iter()
-                .map(|name| self.players.get_id_by_name(name)),
-        )
-        .await
-        .into_iter()
-        .flatten();
+        if settings.persist_shines.profile_file_name(profile).is_none() {
+            return Err(eyre!(
+                "Unknown moon profile {}, add it to persist_shines.profiles",
+                profile
+            ));
+        }
 
-        let mut peers = self.peers.write().await;
+        drop(settings);
 
-        for id in ids {
-            let peer = peers.get_mut(&id);
+        self.persist_shines().await;
 
-            if peer.is_none() {
-                continue;
-            }
+        let mut settings = self.settings.write().await;
+        settings.persist_shines.active_profile = profile.to_owned();
+        settings.save().await;
+        drop(settings);
 
-            let peer = peer.unwrap();
+        self.load_shines().await?;
 
-            peer.disconnect().await;
-            peer.connected = false;
+        // Moons already sent can't be removed from the game, but the ones of the new profile
+        // have to be sent again
+        for player in self.players.all().await {
+            player.write().await.shine_sync.clear();
         }
-    }
-}
 
-async fn receive_packet(reader: &mut ReadHalf<TcpStream>) -> Result<Packet> {
-    let mut header_buf = [0; HEADER_SIZE];
+        self.sync_shine_bag().await;
 
-    match reader.read_exact(&mut header_buf).await {
-        Ok(n) if n == 0 => return Ok(Packet::new(Uuid::nil(), Content::Disconnect)),
-        Ok(_) => (),
-        Err(e) => {
-            debug!("Connection closed: {}", e);
-            return Ok(Packet::new(Uuid::nil(), Content::Disconnect));
+        Ok(())
+    }
+
+    pub async fn persist_players(&self) {
+        let settings = self.settings.read().await;
+        if !settings.persist_players.enabled {
+            return;
+        }
+
+        let file_name = settings.persist_players.file_name.clone();
+        let ttl_days = settings.persist_players.ttl_days;
+
+        drop(settings);
+
+        if ttl_days > 0 {
+            let forgotten = self
+                .players
+                .forget_older_than(Duration::days(i64::from(ttl_days)))
+                .await;
+
+            if forgotten > 0 {
+                info!(
+                    "Forgot {} players not seen for {} days",
+                    forgotten, ttl_days
+                );
+            }
+        }
+
+        let serialized = serde_json::to_string(&self.players.known().await).unwrap();
+
+        let _ = tokio::fs::write(file_name, serialized)
+            .await
+            .map_err(|err| {
+                tracing::error!(%err, "Players file failed to save");
+                err
+            });
+    }
+
+    pub async fn load_players(&self) -> Result<()> {
+        let settings = self.settings.read().await;
+
+        if !settings.persist_players.enabled {
+            return Ok(());
+        }
+
+        let file_name = settings.persist_players.file_name.clone();
+
+        drop(settings);
+
+        let content = match tokio::fs::read_to_string(&file_name).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        self.players
+            .set_known(serde_json::from_str(&content).unwrap_or_default())
+            .await;
+
+        info!("Known players loaded from {}", file_name);
+
+        Ok(())
+    }
+
+    pub async fn persist_costumes(&self) {
+        let settings = self.settings.read().await;
+        if !settings.persist_costumes.enabled {
+            return;
+        }
+
+        let file_name = settings.persist_costumes.file_name.clone();
+        let ttl_days = settings.persist_costumes.ttl_days;
+
+        drop(settings);
+
+        if ttl_days > 0 {
+            let forgotten = self
+                .players
+                .forget_costumes_older_than(Duration::days(i64::from(ttl_days)))
+                .await;
+
+            if forgotten > 0 {
+                info!(
+                    "Forgot {} costumes not worn for {} days",
+                    forgotten, ttl_days
+                );
+            }
+        }
+
+        let serialized = serde_json::to_string(&self.players.costumes().await).unwrap();
+
+        let _ = tokio::fs::write(file_name, serialized)
+            .await
+            .map_err(|err| {
+                tracing::error!(%err, "Costumes file failed to save");
+                err
+            });
+    }
+
+    pub async fn load_costumes(&self) -> Result<()> {
+        let settings = self.settings.read().await;
+
+        if !settings.persist_costumes.enabled {
+            return Ok(());
+        }
+
+        let file_name = settings.persist_costumes.file_name.clone();
+
+        drop(settings);
+
+        let content = match tokio::fs::read_to_string(&file_name).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        self.players
+            .set_costumes(serde_json::from_str(&content).unwrap_or_default())
+            .await;
+
+        info!("Costumes loaded from {}", file_name);
+
+        Ok(())
+    }
+
+    pub async fn load_seed(&self) -> Result<()> {
+        let settings = self.settings.read().await;
+
+        if !settings.seed.enabled {
+            return Ok(());
+        }
+
+        let file_name = settings.seed.file_name.clone();
+
+        drop(settings);
+
+        let content = match tokio::fs::read_to_string(&file_name).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let rules: HashMap<String, Vec<SeedAction>> = match serde_json::from_str(&content) {
+            Ok(rules) => rules,
+            Err(err) => {
+                tracing::error!(%err, "Seed file {} is invalid", file_name);
+                return Err(err.into());
+            }
+        };
+
+        info!("Loaded {} pending rules from {}", rules.len(), file_name);
+
+        *self.pending_rules.write().await = rules;
+
+        Ok(())
+    }
+
+    async fn apply_pending_rules(&self, id: Uuid, name: &str, ip: IpAddr) {
+        let mut pending_rules = self.pending_rules.write().await;
+
+        let actions = match pending_rules.remove(name) {
+            Some(actions) => actions,
+            None => return,
+        };
+
+        let mut settings = self.settings.write().await;
+
+        for action in actions {
+            match action {
+                SeedAction::Ban if !settings.ban_list.ids.contains(&id) => {
+                    settings.ban_list.ban(id, Some(ip))
+                }
+                SeedAction::Flip if !settings.flip.players.contains(&id) => {
+                    settings.flip.players.push(id)
+                }
+                _ => continue,
+            }
+
+            info!("Applied seeded {:?} to [{}] {}", action, name, id);
+        }
+
+        settings.save().await;
+
+        let file_name = settings.seed.file_name.clone();
+
+        drop(settings);
+
+        // The remaining rules are saved so they are not applied again after a restart
+        let serialized = serde_json::to_string_pretty(&*pending_rules).unwrap();
+
+        let _ = tokio::fs::write(file_name, serialized)
+            .await
+            .map_err(|err| {
+                tracing::error!(%err, "Seed file failed to save");
+                err
+            });
+    }
+
+    // Forgets the costume of the player and makes everyone, including them, render the default one
+    pub async fn reset_costume(&self, id: Uuid) -> Result<()> {
+        let player = self
+            .players
+            .get(&id)
+            .await
+            .ok_or_else(|| eyre!("Couldn't find player"))?;
+
+        player.write().await.costume = None;
+        self.players.forget_costume(&id).await;
+
+        self.broadcast_with(
+            Packet::new(
+                id,
+                Content::Costume {
+                    body: "Mario".to_owned(),
+                    cap: "Mario".to_owned(),
+                },
+            ),
+            true,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    // Also sent to the player, so their own costume changes too
+    pub async fn force_costume(&self, id: Uuid, body: String, cap: String) -> Result<()> {
+        let player = self
+            .players
+            .get(&id)
+            .await
+            .ok_or_else(|| eyre!("Couldn't find player"))?;
+
+        player.write().await.set_costume(body.clone(), cap.clone());
+        self.players
+            .remember_costume(
+                id,
+                Costume {
+                    body: body.clone(),
+                    cap: cap.clone(),
+                },
+            )
+            .await;
+
+        self.broadcast_with(Packet::new(id, Content::Costume { body, cap }), true)
+            .await;
+
+        Ok(())
+    }
+
+    // Disconnects the players and prevents them from reconnecting for server.kick_cooldown_secs
+    pub async fn kick(&self, ids: Vec<Uuid>) -> std::time::Duration {
+        let cooldown =
+            std::time::Duration::from_secs(self.settings.read().await.server.kick_cooldown_secs);
+
+        let mut kicked = self.kicked.write().await;
+        kicked.retain(|_, at| at.elapsed() < cooldown);
+
+        let peers = self.peers.read().await;
+
+        for id in ids {
+            kicked.insert(id, Instant::now());
+
+            if let Some(peer) = peers.get(&id) {
+                peer.disconnect().await;
+            }
+        }
+
+        cooldown
+    }
+
+    // Returns the time left before the player can reconnect
+    async fn kick_cooldown(&self, id: &Uuid) -> Option<std::time::Duration> {
+        let cooldown =
+            std::time::Duration::from_secs(self.settings.read().await.server.kick_cooldown_secs);
+
+        self.kicked
+            .read()
+            .await
+            .get(id)
+            .and_then(|at| cooldown.checked_sub(at.elapsed()))
+    }
+
+    async fn bind_addresses(&self) -> Result<Vec<SocketAddr>> {
+        let settings = self.settings.read().await;
+        let port = u16::try_from(settings.server.port)
+            .map_err(|_| eyre!("Invalid port, please check port in settings.json"))?;
+
+        Ok([settings.server.address]
+            .into_iter()
+            .chain(settings.server.additional_addresses.iter().copied())
+            .map(|address| SocketAddr::new(address, port))
+            .collect())
+    }
+
+    // Starts accepting connections on the addresses of the settings. The current listeners are
+    // only replaced if the addresses changed, the connected players aren't affected
+    pub async fn listen(self: &Arc<Self>) -> Result<()> {
+        let addresses = self.bind_addresses().await?;
+        let mut listeners = self.listeners.lock().await;
+
+        if listeners
+            .iter()
+            .map(|(address, _)| *address)
+            .eq(addresses.iter().copied())
+        {
+            return Ok(());
+        }
+
+        let mut previous = vec![];
+
+        // Waiting for the aborted tasks makes sure their listener is dropped, so the same port can
+        // be bound again
+        for (address, task) in listeners.drain(..) {
+            task.abort();
+            let _ = task.await;
+
+            previous.push(address);
+        }
+
+        let bound = match addresses
+            .iter()
+            .map(|address| bind(*address).map(|listener| (*address, listener)))
+            .collect::<Result<Vec<_>>>()
+        {
+            Ok(bound) => bound,
+            Err(e) if !previous.is_empty() => {
+                tracing::error!(error = %e, "Couldn't bind the new addresses, keeping the previous ones");
+
+                previous
+                    .iter()
+                    .map(|address| bind(*address).map(|listener| (*address, listener)))
+                    .collect::<Result<Vec<_>>>()?
+            }
+            Err(e) => return Err(e),
+        };
+
+        for (address, listener) in bound {
+            info!(addr = %address, "Server ready and listening");
+
+            let task = tokio::spawn({
+                let server = self.clone();
+
+                async move {
+                    if let Err(err) = accept_loop(listener, server).await {
+                        tracing::error!(%err, "Stopped accepting connections on {}", address);
+                    }
+                }
+            });
+
+            listeners.push((address, task));
+        }
+
+        Ok(())
+    }
+
+    // Sends the seconds left to the participants until it reaches 0. The time isn't decreased
+    // while tag_countdown_paused is true, so resuming continues from where it was paused
+    pub async fn tag_countdown(&self, participants: &[Uuid], seconds: u32) {
+        self.tag_countdown.store(seconds, Ordering::Relaxed);
+        self.tag_countdown_paused.store(false, Ordering::Relaxed);
+
+        {
+            let mut saved = self.tag_saved_times.lock().await;
+
+            // Times left by an aborted countdown are already overwritten, so they're kept
+            for id in participants {
+                if saved.contains_key(id) {
+                    continue;
+                }
+
+                if let Some(player) = self.players.get(id).await {
+                    saved.insert(*id, player.read().await.time);
+                }
+            }
+        }
+
+        let mut last_sent = None;
+
+        loop {
+            let left = self.tag_countdown.load(Ordering::Relaxed);
+
+            if last_sent != Some(left) {
+                last_sent = Some(left);
+
+                let packet = Packet::new(
+                    Uuid::nil(),
+                    Content::Tag {
+                        update_type: TagUpdate::Time.as_byte(),
+                        is_it: false,
+                        seconds: (left % 60) as u16,
+                        minutes: u16::try_from(left / 60).unwrap_or(u16::MAX),
+                    },
+                );
+
+                for id in participants {
+                    let _ = self.send_to(id, packet.clone()).await;
+                }
+            }
+
+            if left == 0 {
+                break;
+            }
+
+            sleep(std::time::Duration::from_secs(1)).await;
+
+            if !self.tag_countdown_paused.load(Ordering::Relaxed) {
+                // tag stop can set it to 0 in the meantime
+                let _ =
+                    self.tag_countdown
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                            left.checked_sub(1)
+                        });
+            }
+        }
+
+        self.restore_tag_times().await;
+    }
+
+    // Sends back the timers the players had before the countdown
+    pub async fn restore_tag_times(&self) {
+        let saved = std::mem::take(&mut *self.tag_saved_times.lock().await);
+
+        for (id, time) in saved {
+            if let Some(player) = self.players.get(&id).await {
+                player.write().await.time = time;
+            }
+
+            let _ = self
+                .send_to(
+                    &id,
+                    Packet::new(
+                        Uuid::nil(),
+                        Content::Tag {
+                            update_type: TagUpdate::Time.as_byte(),
+                            is_it: false,
+                            seconds: u16::try_from(time.num_seconds() % 60).unwrap_or(0),
+                            minutes: u16::try_from(time.num_minutes()).unwrap_or(u16::MAX),
+                        },
+                    ),
+                )
+                .await;
+        }
+    }
+
+    // Disconnects the players that didn't send anything since timeout, so crashed clients whose
+    // socket is still open don't stay connected. The clients send their position continuously
+    // while playing, so nothing has to be sent to them
+    pub async fn keepalive(&self, interval: std::time::Duration, timeout: std::time::Duration) {
+        loop {
+            sleep(interval).await;
+
+            let peers = self.peers.read().await;
+
+            for peer in peers.values().filter(|p| p.connected) {
+                if peer.last_activity().elapsed() > timeout {
+                    info!(
+                        "Disconnected {}: nothing received for {:?}",
+                        peer.id, timeout
+                    );
+                    peer.disconnect().await;
+                }
+            }
+        }
+    }
+
+    // Disconnects the most recently connected peers until there are at most max connected,
+    // spectators aren't counted
+    pub async fn enforce_max_players(&self, max: usize) -> usize {
+        let peers = self.peers.read().await;
+        let mut connected = peers
+            .values()
+            .filter(|p| p.connected && !p.spectator)
+            .collect::<Vec<_>>();
+
+        if connected.len() <= max {
+            return 0;
+        }
+
+        connected.sort_by_key(|p| p.connected_at);
+        let excess = connected.split_off(max);
+
+        join_all(excess.iter().map(|p| p.disconnect())).await;
+
+        excess.len()
+    }
+
+    // Tells everyone the players left and saves what would otherwise only be saved on a timer
+    pub async fn shutdown(&self) {
+        let connected = self.connected_peers().await;
+
+        for id in connected {
+            self.broadcast(Packet::new(id, Content::Disconnect)).await;
+        }
+
+        self.disconnect_all().await;
+        self.persist_shines().await;
+        self.persist_players().await;
+        self.persist_costumes().await;
+        self.recorder.flush().await;
+        self.settings.read().await.save().await;
+    }
+
+    pub async fn disconnect_all(&self) {
+        let peers = self.peers.read().await;
+
+        join_all(peers.values().map(Peer::disconnect)).await;
+    }
+
+    // Disconnects the players one by one, so they don't all reconnect at the same time
+    pub async fn disconnect_all_staggered(&self, delay: std::time::Duration) {
+        for id in self.connected_peers().await {
+            if let Some(peer) = self.peers.read().await.get(&id) {
+                peer.disconnect().await;
+            }
+
+            sleep(delay).await;
+        }
+    }
+
+    pub async fn disconnect_by_name(&self, players: Vec<String>) {
+        let ids = join_all(
+            players
+                .into_iter()
+                .map(|name| async move { self.players.get_all_ids_by_name(&name).await }),
+        )
+        .await
+        .into_iter()
+        .flatten();
+
+        let mut peers = self.peers.write().await;
+
+        for id in ids {
+            let peer = peers.get_mut(&id);
+
+            if peer.is_none() {
+                continue;
+            }
+
+            let peer = peer.unwrap();
+
+            peer.disconnect().await;
+            peer.connected = false;
+        }
+    }
+}
+
+// IPv6 sockets are IPv6 only, so an IPv4 address can be bound on the same port
+fn bind(address: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    // Same as TcpListener::bind, so the port can be reused right after a restart
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+// Spawned tasks would otherwise lose the span of the connection that spawned them
+fn spawn_in_span<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.in_current_span())
+}
+
+// Connections are handled the same whatever the listener they arrived on
+async fn accept_loop(listener: TcpListener, server: Arc<Server>) -> Result<()> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let server = server.clone();
+
+        tokio::spawn(async move {
+            if let Ok(addr) = socket.peer_addr() {
+                let settings = server.settings.read().await;
+                let is_banned = settings.ban_list.is_ip_ban(&addr.ip());
+                drop(settings);
+
+                if is_banned {
+                    server.metrics.reject(Rejection::Banned);
+                    let _ = socket.shutdown().await;
+                    return;
+                }
+            }
+
+            match socket.set_nodelay(true) {
+                Ok(_) => match server.handle_connection(socket).await {
+                    Ok(_) => (),
+                    Err(message) => {
+                        debug!(error = %message, "handle_connection exited with error")
+                    }
+                },
+                Err(_) => {
+                    debug!("Couldn't set NODELAY to socket, dropping it");
+                    drop(socket)
+                }
+            };
+        });
+    }
+}
+
+// Returns None when the connection is closed
+async fn receive_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+    read_timeout: Option<std::time::Duration>,
+    max_packet_size: usize,
+) -> Result<Option<(Header, Bytes)>> {
+    let mut header_buf = [0; HEADER_SIZE];
+
+    match with_timeout(read_timeout, reader.read_exact(&mut header_buf)).await? {
+        Ok(0) => return Ok(None),
+        Ok(_) => (),
+        Err(e) => {
+            debug!("Connection closed: {}", e);
+            return Ok(None);
         }
     };
 
@@ -894,11 +2631,21 @@ async fn receive_packet(reader: &mut ReadHalf<TcpStream>) -> Result<Packet> {
         }
     };
 
+    // Checked before allocating the body, the size comes from the client
+    if header.packet_size > max_packet_size {
+        info!(
+            "Disconnected {}: sent a packet of {} bytes, the max is {}",
+            header.id, header.packet_size, max_packet_size
+        );
+
+        return Err(eyre!("Packet too big"));
+    }
+
     let body = if header.packet_size > 0 {
         let mut body_buf = vec![0; header.packet_size];
 
-        match reader.read_exact(&mut body_buf).await {
-            Ok(n) if n == 0 => return Err(eyre!("End of file reached")),
+        match with_timeout(read_timeout, reader.read_exact(&mut body_buf)).await? {
+            Ok(0) => return Err(eyre!("End of file reached")),
             Ok(_) => (),
             Err(e) => {
                 debug!("Error reading header {}", e);
@@ -911,5 +2658,723 @@ async fn receive_packet(reader: &mut ReadHalf<TcpStream>) -> Result<Packet> {
         Bytes::new()
     };
 
-    header.make_packet(body)
+    Ok(Some((header, body)))
+}
+
+// A client that stalls mid packet would otherwise keep its slot forever
+async fn with_timeout<T>(
+    read_timeout: Option<std::time::Duration>,
+    future: impl Future<Output = T>,
+) -> Result<T> {
+    match read_timeout {
+        Some(read_timeout) => timeout(read_timeout, future)
+            .await
+            .map_err(|_| eyre!("Nothing received for {:?}", read_timeout)),
+        None => Ok(future.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use glam::{Quat, Vec3};
+    use tokio::io::{duplex, AsyncWriteExt};
+    use tokio::time::{sleep, Instant};
+    use uuid::Uuid;
+
+    use super::{receive_frame, Server};
+    use crate::packet::Packet;
+    use crate::packet::{ConnectionType, Content};
+    use crate::players::Costume;
+    use crate::settings::Settings;
+    use crate::testing::{start, wait_for, Client};
+
+    fn header(size: i16) -> Vec<u8> {
+        let mut header = Uuid::new_v4().as_bytes().to_vec();
+        header.extend_from_slice(&2i16.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header
+    }
+
+    #[tokio::test]
+    async fn receive_frame_reads_the_body() {
+        let (mut client, mut server) = duplex(256);
+
+        client.write_all(&header(4)).await.unwrap();
+        client.write_all(&[1, 2, 3, 4]).await.unwrap();
+
+        let (header, body) = receive_frame(&mut server, None, 1024)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(header.packet_size, 4);
+        assert_eq!(&body[..], &[1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn receive_frame_rejects_an_oversized_header() {
+        let (mut client, mut server) = duplex(256);
+
+        // The body is never sent, the header alone must be rejected
+        client.write_all(&header(2048)).await.unwrap();
+
+        assert!(receive_frame(&mut server, None, 1024).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn receive_frame_times_out_on_a_partial_header() {
+        let (mut client, mut server) = duplex(256);
+        let start = Instant::now();
+
+        // The client stays connected but never sends the rest of the header
+        client.write_all(&header(4)[..5]).await.unwrap();
+
+        assert!(
+            receive_frame(&mut server, Some(Duration::from_secs(10)), 1024)
+                .await
+                .is_err()
+        );
+        assert!(start.elapsed() >= Duration::from_secs(10));
+
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn receive_frame_returns_none_when_closed() {
+        let (client, mut server) = duplex(256);
+
+        drop(client);
+
+        assert!(receive_frame(&mut server, None, 1024)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn full_server_only_evicts_for_accepted_players() {
+        let banned = Uuid::new_v4();
+        let mut settings = Settings::default();
+        settings.server.max_players = 1;
+        settings.server.evict_when_full = true;
+        settings.ban_list.enabled = true;
+        settings.ban_list.ids.push(banned);
+
+        let (server, addr) = start(settings).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+
+        let mut bob = Client::open(addr, banned, "bob", ConnectionType::First).await;
+        assert!(bob.is_closed().await);
+
+        // Only one connection per ip, so the second local player is rejected too
+        server.settings.write().await.server.max_connections_per_ip = 1;
+        let mut carol = Client::open(addr, Uuid::new_v4(), "carol", ConnectionType::First).await;
+        assert!(carol.is_closed().await);
+
+        assert!(!alice.is_closed().await);
+        assert_eq!(server.connected_peers().await, vec![alice.id]);
+
+        server.settings.write().await.server.max_connections_per_ip = 0;
+        let dave = Client::connect(&server, addr, "dave").await;
+
+        assert!(alice.is_closed().await);
+        drop(alice);
+
+        assert!(wait_for(|| async { server.connected_peers().await == vec![dave.id] }).await);
+    }
+
+    #[tokio::test]
+    async fn disconnected_peers_leave_room() {
+        let mut settings = Settings::default();
+        settings.server.max_players = 1;
+
+        let (server, addr) = start(settings).await;
+        let alice = Client::connect(&server, addr, "alice").await;
+        drop(alice);
+
+        assert!(wait_for(|| async { server.connected_peers().await.is_empty() }).await);
+
+        // Would be rejected if alice's peer still counted
+        let bob = Client::connect(&server, addr, "bob").await;
+
+        assert_eq!(server.connected_peers().await, vec![bob.id]);
+    }
+
+    #[tokio::test]
+    async fn new_save_keeps_the_moons_of_the_others() {
+        for per_player_new_save in [true, false] {
+            let mut settings = Settings::default();
+            settings.persist_shines.per_player_new_save = per_player_new_save;
+
+            let (server, addr) = start(settings).await;
+            let mut alice = Client::connect(&server, addr, "alice").await;
+            let bob = Client::connect(&server, addr, "bob").await;
+
+            server.shine_bag.write().await.insert((5, false));
+            let player = server.players.get(&bob.id).await.unwrap();
+            player.write().await.shine_sync.insert((5, false));
+
+            alice
+                .send(Content::Game {
+                    is_2d: false,
+                    scenario: 0,
+                    stage: "CapWorldHomeStage".to_owned(),
+                })
+                .await;
+
+            let alice_player = server.players.get(&alice.id).await.unwrap();
+            assert!(wait_for(|| async { alice_player.read().await.is_speedrun }).await);
+
+            assert_eq!(
+                server.shine_bag.read().await.contains(&(5, false)),
+                per_player_new_save
+            );
+            assert!(player.read().await.shine_sync.contains(&(5, false)));
+        }
+    }
+
+    #[tokio::test]
+    async fn persisting_fewer_moons_replaces_the_file() {
+        let path = std::env::temp_dir().join(format!("smo-moons-{}.json", Uuid::new_v4()));
+        let mut settings = Settings::default();
+        settings.persist_shines.enabled = true;
+        settings.persist_shines.file_name = path.to_string_lossy().into_owned();
+
+        let server = Server::new(settings);
+
+        *server.shine_bag.write().await = (0..500).map(|id| (id, id % 2 == 0)).collect();
+        server.persist_shines().await;
+
+        *server.shine_bag.write().await = [(1, false), (2, true)].into_iter().collect();
+        server.persist_shines().await;
+
+        server.shine_bag.write().await.clear();
+        server.load_shines().await.unwrap();
+
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(
+            *server.shine_bag.read().await,
+            [(1, false), (2, true)].into_iter().collect()
+        );
+    }
+
+    #[tokio::test]
+    async fn player_packets_update_the_last_position() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let player = server.players.get(&alice.id).await.unwrap();
+
+        assert_eq!(player.read().await.last_position(), None);
+
+        let position = Vec3::new(1., 2.5, -3.);
+
+        alice
+            .send(Content::Player {
+                position,
+                quaternion: Quat::IDENTITY,
+                animation_blend_weights: vec![0.; 6],
+                act: 0,
+                subact: 0,
+            })
+            .await;
+
+        assert!(wait_for(|| async { player.read().await.last_position() == Some(position) }).await);
+    }
+
+    #[tokio::test]
+    async fn flood_disconnects_the_player() {
+        let mut settings = Settings::default();
+        settings.server.max_packets_per_second = 10;
+
+        let (server, addr) = start(settings).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+
+        for _ in 0..50 {
+            alice
+                .send(Content::Costume {
+                    body: "Mario".to_owned(),
+                    cap: "Mario".to_owned(),
+                })
+                .await;
+        }
+
+        assert!(alice.is_closed().await);
+        assert!(wait_for(|| async { server.connected_peers().await.is_empty() }).await);
+    }
+
+    #[tokio::test]
+    async fn stage_overrides_the_scenario_merge() {
+        let mut settings = Settings::default();
+        settings.scenario.merge_enabled = false;
+        settings
+            .scenario
+            .stages
+            .insert("CapWorldHomeStage".to_owned(), true);
+
+        let (server, addr) = start(settings).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let mut bob = Client::connect(&server, addr, "bob").await;
+
+        let game = |scenario, stage: &str| Content::Game {
+            is_2d: false,
+            scenario,
+            stage: stage.to_owned(),
+        };
+
+        bob.send(game(3, "SandWorldHomeStage")).await;
+        alice
+            .recv_until(|p| matches!(p.content, Content::Game { .. }))
+            .await
+            .unwrap();
+
+        // Merged in Cap, bob receives it with their own scenario
+        for (stage, expected) in [("CapWorldHomeStage", 3), ("LakeWorldHomeStage", 5)] {
+            alice.send(game(5, stage)).await;
+
+            let received = bob
+                .recv_until(|p| matches!(p.content, Content::Game { .. }))
+                .await
+                .map(|p| p.content);
+
+            assert!(
+                matches!(received, Some(Content::Game { scenario, .. }) if scenario == expected),
+                "{} {:?}",
+                stage,
+                received
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_are_limited_per_ip() {
+        let mut settings = Settings::default();
+        settings.server.max_connections_per_ip = 2;
+
+        let (server, addr) = start(settings).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let _bob = Client::connect(&server, addr, "bob").await;
+
+        let mut carol = Client::open(addr, Uuid::new_v4(), "carol", ConnectionType::First).await;
+        assert!(carol.is_closed().await);
+        assert_eq!(server.connected_peers().await.len(), 2);
+
+        // The previous connection of a reconnecting player doesn't count, it's replaced
+        let _reconnected = Client::connect_as(&server, addr, alice.id, "alice").await;
+        assert!(alice.is_closed().await);
+    }
+
+    #[tokio::test]
+    async fn broadcast_to_stage_only_reaches_the_stage() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let mut bob = Client::connect(&server, addr, "bob").await;
+
+        for (client, stage) in [
+            (&mut alice, "CapWorldHomeStage"),
+            (&mut bob, "SandWorldHomeStage"),
+        ] {
+            client
+                .send(Content::Game {
+                    is_2d: false,
+                    scenario: 1,
+                    stage: stage.to_owned(),
+                })
+                .await;
+        }
+
+        assert!(
+            wait_for(|| async {
+                server.stage_occupancy("CapWorldHomeStage").await == 1
+                    && server.stage_occupancy("SandWorldHomeStage").await == 1
+            })
+            .await
+        );
+
+        server
+            .broadcast_to_stage(
+                "CapWorldHomeStage",
+                Packet::new(
+                    Uuid::nil(),
+                    Content::ChangeStage {
+                        id: "".to_owned(),
+                        stage: "LakeWorldHomeStage".to_owned(),
+                        scenario: -1,
+                        sub_scenario: 0,
+                    },
+                ),
+            )
+            .await;
+
+        let is_change_stage = |p: &Packet| matches!(p.content, Content::ChangeStage { .. });
+
+        assert!(alice.recv_until(is_change_stage).await.is_some());
+        assert!(bob.recv_until(is_change_stage).await.is_none());
+    }
+
+    fn costume(name: &str) -> Content {
+        Content::Costume {
+            body: name.to_owned(),
+            cap: name.to_owned(),
+        }
+    }
+
+    // Costume of `id` received by the client
+    async fn received_costume(client: &mut Client, id: Uuid) -> Option<String> {
+        let packet = client
+            .recv_until(|p| p.id == id && matches!(p.content, Content::Costume { .. }))
+            .await?;
+
+        match packet.content {
+            Content::Costume { body, cap } => Some(format!("{} {}", body, cap)),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn costume_filter_rewrites_the_filtered_players() {
+        let alice_id = Uuid::new_v4();
+        let mut settings = Settings::default();
+        settings.costume_filter.enabled = true;
+        settings.costume_filter.players.push(alice_id);
+        settings.costume_filter.cap = "MarioCap".to_owned();
+
+        let (server, addr) = start(settings).await;
+        let mut alice = Client::connect_as(&server, addr, alice_id, "alice").await;
+        let mut bob = Client::connect(&server, addr, "bob").await;
+
+        alice.send(costume("MarioTuxedo")).await;
+        assert_eq!(
+            received_costume(&mut bob, alice.id).await.as_deref(),
+            Some("Mario MarioCap")
+        );
+
+        bob.send(costume("MarioTuxedo")).await;
+        assert_eq!(
+            received_costume(&mut alice, bob.id).await.as_deref(),
+            Some("MarioTuxedo MarioTuxedo")
+        );
+    }
+
+    #[tokio::test]
+    async fn special_costumes_need_to_be_allowed() {
+        let bob_id = Uuid::new_v4();
+        let mut settings = Settings::default();
+        settings.special_costumes.costumes = vec!["MarioInvisible".to_owned()];
+        settings.special_costumes.allowed_players = vec![bob_id];
+
+        let (server, addr) = start(settings).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let mut bob = Client::connect_as(&server, addr, bob_id, "bob").await;
+
+        alice.send(costume("MarioInvisible")).await;
+        assert_eq!(
+            received_costume(&mut bob, alice.id).await.as_deref(),
+            Some("Mario Mario")
+        );
+
+        bob.send(costume("MarioInvisible")).await;
+        assert_eq!(
+            received_costume(&mut alice, bob.id).await.as_deref(),
+            Some("MarioInvisible MarioInvisible")
+        );
+    }
+
+    #[tokio::test]
+    async fn players_are_removed_after_the_grace_window() {
+        let mut settings = Settings::default();
+        settings.server.player_cleanup_secs = 1;
+
+        let (server, addr) = start(settings).await;
+        let alice = Client::connect(&server, addr, "alice").await;
+        let bob = Client::connect(&server, addr, "bob").await;
+        let (alice_id, bob_id) = (alice.id, bob.id);
+
+        drop(alice);
+        drop(bob);
+        assert!(wait_for(|| async { server.connected_peers().await.is_empty() }).await);
+
+        // Reconnecting within the grace window keeps the player
+        let _bob = Client::connect_as(&server, addr, bob_id, "bob").await;
+
+        assert!(wait_for(|| async { server.players.get(&alice_id).await.is_none() }).await);
+        assert!(!server.peers.read().await.contains_key(&alice_id));
+
+        sleep(Duration::from_secs(1)).await;
+        assert!(server.players.get(&bob_id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn reconnecting_players_receive_the_new_moons() {
+        let (server, addr) = start(Settings::default()).await;
+        let alice = Client::connect(&server, addr, "alice").await;
+        let id = alice.id;
+
+        drop(alice);
+        assert!(wait_for(|| async { server.connected_peers().await.is_empty() }).await);
+
+        server.shine_bag.write().await.insert((9, true));
+
+        let mut alice = Client::open(addr, id, "alice", ConnectionType::Reconnect).await;
+        let shine = alice
+            .recv_until(|p| matches!(p.content, Content::Shine { .. }))
+            .await
+            .map(|p| p.content);
+
+        assert!(
+            matches!(
+                shine,
+                Some(Content::Shine {
+                    id: 9,
+                    is_grand: true
+                })
+            ),
+            "{:?}",
+            shine
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_without_a_player_are_disconnected() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+
+        // Simulates a race removing the player while its peer is still connected
+        server.players.remove(&alice.id).await;
+
+        let bob = Client::connect(&server, addr, "bob").await;
+
+        assert!(alice.is_closed().await);
+        drop(alice);
+
+        assert!(wait_for(|| async { server.connected_peers().await == vec![bob.id] }).await);
+    }
+
+    #[tokio::test]
+    async fn capture_packets_update_the_captured_model() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let player = server.players.get(&alice.id).await.unwrap();
+
+        alice
+            .send(Content::Capture {
+                model: "Kuribo".to_owned(),
+            })
+            .await;
+
+        assert!(
+            wait_for(|| async { player.read().await.captured.as_deref() == Some("Kuribo") }).await
+        );
+
+        // Sent when the capture ends
+        alice
+            .send(Content::Capture {
+                model: "".to_owned(),
+            })
+            .await;
+
+        assert!(wait_for(|| async { player.read().await.captured.is_none() }).await);
+    }
+
+    #[tokio::test]
+    async fn costumes_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("smo-costumes-{}.json", Uuid::new_v4()));
+        let settings = || {
+            let mut settings = Settings::default();
+            settings.persist_costumes.enabled = true;
+            settings.persist_costumes.file_name = path.to_string_lossy().into_owned();
+            settings
+        };
+
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let server = Server::new(settings());
+
+        for (id, name) in [(alice, "MarioTuxedo"), (bob, "MarioClown")] {
+            server
+                .players
+                .remember_costume(
+                    id,
+                    Costume {
+                        body: name.to_owned(),
+                        cap: name.to_owned(),
+                    },
+                )
+                .await;
+        }
+
+        // Bob's costume wasn't worn for longer than persist_costumes.ttl_days
+        let mut costumes = server.players.costumes().await;
+        costumes.get_mut(&bob).unwrap().last_seen = Utc::now() - chrono::Duration::days(31);
+        server.players.set_costumes(costumes).await;
+
+        server.persist_costumes().await;
+
+        let restarted = Server::new(settings());
+        restarted.load_costumes().await.unwrap();
+
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(
+            restarted
+                .players
+                .known_costume(&alice)
+                .await
+                .map(|costume| costume.body),
+            Some("MarioTuxedo".to_owned())
+        );
+        assert!(restarted.players.known_costume(&bob).await.is_none());
+    }
+
+    fn game(stage: &str) -> Content {
+        Content::Game {
+            is_2d: false,
+            scenario: 1,
+            stage: stage.to_owned(),
+        }
+    }
+
+    fn cap() -> Content {
+        Content::Cap {
+            position: Vec3::ZERO,
+            quaternion: Quat::IDENTITY,
+            cap_out: true,
+            cap_anim: vec![0; 0x30],
+        }
+    }
+
+    #[tokio::test]
+    async fn caps_stay_in_the_stage_they_were_thrown() {
+        let mut settings = Settings::default();
+        settings.server.stage_scoping = true;
+
+        let (server, addr) = start(settings).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let mut bob = Client::connect(&server, addr, "bob").await;
+        let mut carol = Client::connect(&server, addr, "carol").await;
+
+        alice.send(game("CapWorldHomeStage")).await;
+        bob.send(game("SandWorldHomeStage")).await;
+        carol.send(game("CapWorldHomeStage")).await;
+
+        assert!(
+            wait_for(|| async { server.stage_occupancy("CapWorldHomeStage").await == 2 }).await
+        );
+        assert!(
+            wait_for(|| async { server.stage_occupancy("SandWorldHomeStage").await == 1 }).await
+        );
+
+        alice.send(cap()).await;
+
+        let is_cap = |p: &Packet| matches!(p.content, Content::Cap { .. });
+
+        assert!(carol.recv_until(is_cap).await.is_some());
+        assert!(bob.recv_until(is_cap).await.is_none());
+
+        // So it isn't replayed to the players entering the new stage
+        let player = server.players.get(&alice.id).await.unwrap();
+        assert!(player.read().await.last_cap.is_some());
+
+        alice.send(game("LakeWorldHomeStage")).await;
+
+        assert!(wait_for(|| async { player.read().await.last_cap.is_none() }).await);
+    }
+
+    fn movement() -> Content {
+        Content::Player {
+            position: Vec3::new(1., 2., 3.),
+            quaternion: Quat::IDENTITY,
+            animation_blend_weights: vec![0.; 6],
+            act: 0,
+            subact: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn movement_waits_for_the_first_game_packet() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let mut bob = Client::connect(&server, addr, "bob").await;
+
+        bob.send(game("CapWorldHomeStage")).await;
+
+        let is_movement = |p: &Packet| matches!(p.content, Content::Player { .. });
+
+        alice.send(movement()).await;
+        assert!(bob.recv_until(is_movement).await.is_none());
+
+        alice.send(game("CapWorldHomeStage")).await;
+        alice.send(movement()).await;
+        assert!(bob.recv_until(is_movement).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn reconnecting_players_keep_their_costume() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let id = alice.id;
+
+        alice.send(costume("MarioTuxedo")).await;
+        assert!(wait_for(|| async { server.players.known_costume(&id).await.is_some() }).await);
+
+        // The second time the player was cleaned up, only the remembered costume is left
+        for cleaned_up in [false, true] {
+            drop(alice);
+            assert!(wait_for(|| async { server.connected_peers().await.is_empty() }).await);
+
+            if cleaned_up {
+                server.peers.write().await.remove(&id);
+                server.players.remove(&id).await;
+            }
+
+            alice = Client::connect_as(&server, addr, id, "alice").await;
+
+            let mut bob = Client::open(addr, Uuid::new_v4(), "bob", ConnectionType::First).await;
+
+            assert_eq!(
+                received_costume(&mut bob, id).await.as_deref(),
+                Some("MarioTuxedo MarioTuxedo"),
+                "cleaned up: {}",
+                cleaned_up
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unreadable_moon_file_disables_the_persistence() {
+        let mut settings = Settings::default();
+        settings.persist_shines.enabled = true;
+        // A directory can't be opened as a file
+        settings.persist_shines.file_name = std::env::temp_dir().to_string_lossy().into_owned();
+
+        let server = Server::new(settings);
+
+        assert!(server.load_shines().await.is_err());
+        assert!(server.shine_persistence_failed.load(Ordering::Relaxed));
+
+        // Returns without writing instead of failing every time
+        server.shine_bag.write().await.insert((1, false));
+        server.persist_shines().await;
+    }
+
+    #[tokio::test]
+    async fn chat_messages_from_clients_are_not_relayed() {
+        let (server, addr) = start(Settings::default()).await;
+        let mut alice = Client::connect(&server, addr, "alice").await;
+        let mut bob = Client::connect(&server, addr, "bob").await;
+
+        alice
+            .send(Content::ChatMessage {
+                message: "hello".to_owned(),
+            })
+            .await;
+
+        assert!(bob
+            .recv_until(|p| matches!(p.content, Content::ChatMessage { .. }))
+            .await
+            .is_none());
+    }
 }
@@ -1,11 +1,17 @@
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
-use tracing::info;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+use crate::packet::Content;
+
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize, Clone)]
 pub enum FlipPov {
     Both,
@@ -43,13 +49,27 @@ impl Default for FlipPov {
     }
 }
 
-#[derive(Default, Deserialize, Serialize)]
+#[derive(Default, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Flip {
     pub enabled: bool,
     pub players: Vec<Uuid>,
     pub pov: FlipPov,
 }
 
+impl Flip {
+    pub fn flip_in(&self, id: &Uuid) -> bool {
+        self.enabled
+            && (self.pov == FlipPov::Both || self.pov == FlipPov::Others)
+            && self.players.contains(id)
+    }
+
+    pub fn flip_not_in(&self, id: &Uuid) -> bool {
+        self.enabled
+            && (self.pov == FlipPov::Both || self.pov == FlipPov::Self_)
+            && !self.players.contains(id)
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct SpecialCostumes {
     pub costumes: Vec<String>,
@@ -65,11 +85,85 @@ impl Default for SpecialCostumes {
     }
 }
 
+// When enabled, the costume of these players is replaced by body and cap for everyone, e.g. for
+// race categories where the cosmetics are constrained
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct CostumeFilter {
+    pub enabled: bool,
+    pub players: Vec<Uuid>,
+    pub body: String,
+    pub cap: String,
+}
+
+impl Default for CostumeFilter {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            players: vec![],
+            body: "Mario".to_owned(),
+            cap: "Mario".to_owned(),
+        }
+    }
+}
+
+impl CostumeFilter {
+    pub fn is_filtered(&self, id: &Uuid) -> bool {
+        self.enabled && self.players.contains(id)
+    }
+}
+
+// When enabled, only these ids can join. The ban list is still checked first
+#[derive(Default, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AllowList {
+    pub enabled: bool,
+    pub ids: Vec<Uuid>,
+}
+
+// A client joins as a spectator when its id is in ids, which can be done with spectate <user id>.
+// Spectators receive every packet, but what they send isn't relayed, the others never see them
+// and they don't count toward max_players
 #[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Spectators {
+    pub ids: Vec<Uuid>,
+}
+
+impl AllowList {
+    pub fn is_allowed(&self, id: &Uuid) -> bool {
+        !self.enabled || self.ids.contains(id)
+    }
+}
+
+#[derive(Default, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct BanList {
     pub enabled: bool,
     pub ids: Vec<Uuid>,
-    pub ips: Vec<IpAddr>,
+    // Bare ips written by older versions are read as a single address range
+    #[serde(deserialize_with = "deserialize_ip_ranges")]
+    pub ips: Vec<IpNet>,
+    // Ban the players that are disconnected for sending too many malformed packets
+    pub auto_ban_malformed: bool,
+}
+
+// Accepts a cidr like 192.168.1.0/24 or a bare ip, which is a /32 or a /128
+pub fn parse_ip_range(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .map(|range| range.trunc())
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{} isn't an ip or a cidr range", s))
+}
+
+fn deserialize_ip_ranges<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|range| parse_ip_range(range).map_err(serde::de::Error::custom))
+        .collect()
 }
 
 impl BanList {
@@ -77,19 +171,71 @@ impl BanList {
         self.ids.push(id);
 
         if let Some(ip) = ip {
-            self.ips.push(ip);
+            self.ban_range(IpNet::from(ip));
         }
     }
 
+    // Returns false if the range was already banned
+    pub fn ban_range(&mut self, range: IpNet) -> bool {
+        if self.ips.contains(&range) {
+            return false;
+        }
+
+        self.ips.push(range);
+
+        true
+    }
+
+    // Returns false if the id wasn't banned
+    pub fn unban_id(&mut self, id: &Uuid) -> bool {
+        let len = self.ids.len();
+        self.ids.retain(|banned| banned != id);
+
+        self.ids.len() != len
+    }
+
+    // Returns false if the range wasn't banned, a single ip inside a banned range isn't removed
+    pub fn unban_ip(&mut self, range: &IpNet) -> bool {
+        let len = self.ips.len();
+        self.ips.retain(|banned| banned != range);
+
+        self.ips.len() != len
+    }
+
     pub fn is_ip_ban(&self, ip: &IpAddr) -> bool {
-        self.ips.contains(ip)
+        self.ips.iter().any(|range| range.contains(ip))
     }
 }
 
 #[derive(Deserialize, Serialize)]
+#[serde(default)]
 pub struct PersistShines {
     pub enabled: bool,
+    // File of the default profile
     pub file_name: String,
+    // Profile name -> file name
+    pub profiles: HashMap<String, String>,
+    pub active_profile: String,
+    // Starting a new save only resets that player's sync instead of clearing the shared bag
+    pub per_player_new_save: bool,
+}
+
+impl PersistShines {
+    pub const DEFAULT_PROFILE: &'static str = "default";
+
+    pub fn profile_file_name(&self, profile: &str) -> Option<String> {
+        if profile == Self::DEFAULT_PROFILE {
+            Some(self.file_name.clone())
+        } else {
+            self.profiles.get(profile).cloned()
+        }
+    }
+
+    // Falls back to the default profile if the active one was removed from the settings
+    pub fn active_file_name(&self) -> String {
+        self.profile_file_name(&self.active_profile)
+            .unwrap_or_else(|| self.file_name.clone())
+    }
 }
 
 impl Default for PersistShines {
@@ -97,66 +243,415 @@ impl Default for PersistShines {
         Self {
             enabled: false,
             file_name: String::from("./moons.json"),
+            profiles: HashMap::new(),
+            active_profile: String::from(Self::DEFAULT_PROFILE),
+            per_player_new_save: false,
         }
     }
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct PersistPlayers {
+    pub enabled: bool,
+    pub file_name: String,
+    // Players that weren't seen for that many days are forgotten, 0 keeps them forever
+    pub ttl_days: u32,
+}
+
+impl Default for PersistPlayers {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file_name: String::from("./players.json"),
+            ttl_days: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct PersistCostumes {
+    pub enabled: bool,
+    pub file_name: String,
+    // Costumes that weren't worn for that many days are forgotten, 0 keeps them forever
+    pub ttl_days: u32,
+}
+
+impl Default for PersistCostumes {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file_name: String::from("./costumes.json"),
+            ttl_days: 30,
+        }
+    }
+}
+
+// Actions of seed.json, applied to the players with that name when they first connect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeedAction {
+    Ban,
+    Flip,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Seed {
+    pub enabled: bool,
+    pub file_name: String,
+}
+
+impl Default for Seed {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file_name: String::from("./seed.json"),
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct Tag {
+    // Hiders don't receive the position of the seekers
+    pub hide_seekers: bool,
+    // Distance under which hiders see the seekers anyway, 0 hides them everywhere
+    pub reveal_radius: f32,
+    // Logs when a seeker gets closer than that to a hider, 0 disables it
+    pub proximity_radius: f32,
+}
+
 #[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Events {
+    // Connections and disconnections are written there as json lines, - for stdout
+    pub jsonl_path: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuditLog {
+    pub enabled: bool,
+    // Every command run from the console or the admin api is appended there as a json line
+    pub file_name: String,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file_name: String::from("./audit.jsonl"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Record {
+    // Only applied to the players connecting after it's enabled
+    pub enabled: bool,
+    // Every packet received is appended there, it can be read with --replay
+    pub file_name: String,
+}
+
+impl Default for Record {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file_name: String::from("./packets.bin"),
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Webhook {
+    pub enabled: bool,
+    // Connections and disconnections are sent there as json with a POST request
+    pub url: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Prometheus {
+    pub enabled: bool,
+    pub address: IpAddr,
+    pub port: u32,
+}
+
+impl Default for Prometheus {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: IpAddr::from_str("127.0.0.1").unwrap(),
+            port: 9090,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Admin {
+    pub enabled: bool,
+    pub address: IpAddr,
+    pub port: u32,
+    // Required in every request when the api isn't only bound to loopback
+    pub token: Option<String>,
+}
+
+impl Default for Admin {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: IpAddr::from_str("127.0.0.1").unwrap(),
+            port: 1028,
+            token: None,
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct Scenario {
     pub merge_enabled: bool,
+    // Overrides merge_enabled in these stages, e.g. {"CapWorldHomeStage": true}
+    pub stages: HashMap<String, bool>,
+}
+
+impl Scenario {
+    pub fn merge_in(&self, stage: &str) -> bool {
+        self.stages
+            .get(stage)
+            .copied()
+            .unwrap_or(self.merge_enabled)
+    }
 }
 
 #[derive(Deserialize, Serialize)]
+#[serde(default)]
 pub struct Server {
     pub address: IpAddr,
+    // Also listened on with the same port, e.g. "::" to accept IPv6 next to 0.0.0.0
+    pub additional_addresses: Vec<IpAddr>,
     pub port: u32,
     pub max_players: i16,
+    // Hide the players ip in the console, useful when the logs are public
+    pub hide_ips: bool,
+    // Packet types received from players that won't be relayed, e.g. "cap" or "tag"
+    pub blocked_packet_types: Vec<String>,
+    // Max number of background tasks a connection can have running at the same time
+    pub max_tasks_per_connection: usize,
+    // Movement is only sent to players in the same stage, this does the same for costumes
+    pub stage_scoping: bool,
+    // Players are disconnected after sending that many malformed packets, 0 disconnects on the first one
+    pub max_malformed_packets: u32,
+    // When the server is full, disconnect the least recently active player to let a new one join
+    pub evict_when_full: bool,
+    // Kicked players can't reconnect for that many seconds
+    pub kick_cooldown_secs: u64,
+    // Players sending more packets than that per second are disconnected, 0 disables the limit
+    pub max_packets_per_second: u32,
+    // Players that don't send anything for that many seconds are disconnected. Disabled by
+    // default with 0, since suspended consoles and long loadings don't send anything either.
+    // Otherwise at least 10
+    pub read_timeout_secs: u64,
+    // Seconds between each check of the silent players, 0 disables the keepalive. Nothing is sent
+    // to the players, every packet type belongs to the client protocol
+    pub keepalive_interval_secs: u64,
+    // Players that didn't send anything for that many seconds are disconnected by the keepalive
+    pub keepalive_timeout_secs: u64,
+    // Max number of players send and sendall can put in a stage, e.g. {"CapWorldHomeStage": 4}
+    pub stage_caps: HashMap<String, u16>,
+    // Players that didn't reconnect for that many seconds are removed, 0 keeps them until restart
+    pub player_cleanup_secs: u64,
+    // Max number of players connected from the same ip, 0 disables the limit
+    pub max_connections_per_ip: usize,
+    // Players sending a packet bigger than that many bytes are disconnected, at least
+    // Content::LARGEST_BODY_SIZE
+    pub max_packet_size: usize,
+    // Between 0 and 1, the movement sent to the others is smoothed by keeping that part of the
+    // previous position. Higher is smoother but lags further behind, 0 disables it
+    pub position_smoothing: f32,
 }
 
 impl Default for Server {
     fn default() -> Self {
         Self {
             address: IpAddr::from_str("0.0.0.0").unwrap(),
+            additional_addresses: vec![],
             port: 1027,
             max_players: 8,
+            hide_ips: false,
+            blocked_packet_types: vec![],
+            max_tasks_per_connection: 64,
+            stage_scoping: false,
+            max_malformed_packets: 10,
+            evict_when_full: false,
+            kick_cooldown_secs: 30,
+            max_packets_per_second: 300,
+            read_timeout_secs: 0,
+            keepalive_interval_secs: 0,
+            keepalive_timeout_secs: 30,
+            stage_caps: HashMap::new(),
+            player_cleanup_secs: 600,
+            max_connections_per_ip: 0,
+            max_packet_size: 1024,
+            position_smoothing: 0.,
         }
     }
 }
 
 #[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Settings {
     pub server: Server,
     pub ban_list: BanList,
+    pub allow_list: AllowList,
+    pub spectators: Spectators,
     pub scenario: Scenario,
     pub persist_shines: PersistShines,
+    pub persist_players: PersistPlayers,
+    pub persist_costumes: PersistCostumes,
+    pub seed: Seed,
+    pub tag: Tag,
+    pub events: Events,
+    pub webhook: Webhook,
+    pub audit_log: AuditLog,
+    pub record: Record,
+    pub admin: Admin,
+    pub prometheus: Prometheus,
     pub flip: Flip,
     pub special_costumes: SpecialCostumes,
+    pub costume_filter: CostumeFilter,
 }
 
+// Those fields are only read when the server starts, so updating them at runtime would lie
+const READ_ONLY_PATHS: [&str; 9] = [
+    "server.address",
+    "server.additional_addresses",
+    "server.port",
+    "admin.enabled",
+    "admin.address",
+    "admin.port",
+    "prometheus.enabled",
+    "prometheus.address",
+    "prometheus.port",
+];
+
+// Shorter read timeouts would disconnect the players during the long loadings
+const MIN_READ_TIMEOUT_SECS: u64 = 10;
+
 impl Settings {
     #[inline(always)]
     fn path_buf() -> PathBuf {
+        // The commands save the settings, the tests mustn't replace the ones of the repository
+        if cfg!(test) {
+            return std::env::temp_dir().join(format!("smo-settings-{}.json", std::process::id()));
+        }
+
         PathBuf::from("./settings.json")
     }
 
-    pub async fn load() -> Self {
+    #[inline(always)]
+    fn profiles_dir() -> PathBuf {
+        PathBuf::from("./profiles")
+    }
+
+    // Only letters, digits, - and _ so a slot can't point outside of the profiles directory
+    pub fn profile_path(slot: &str) -> Result<PathBuf, String> {
+        let is_valid = !slot.is_empty()
+            && slot
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+        if !is_valid {
+            return Err(format!(
+                "Invalid profile name {}, only letters, digits, - and _ are allowed",
+                slot
+            ));
+        }
+
+        Ok(Self::profiles_dir().join(format!("{}.json", slot)))
+    }
+
+    // Names of the saved profiles, sorted
+    pub async fn profiles() -> Vec<String> {
+        let mut entries = match tokio::fs::read_dir(Self::profiles_dir()).await {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+
+        let mut profiles = vec![];
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+
+            if path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                    profiles.push(name.to_owned());
+                }
+            }
+        }
+
+        profiles.sort();
+
+        profiles
+    }
+
+    pub async fn load_from(path: &Path) -> Result<Self, String> {
+        let body = tokio::fs::read(path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+        let mut settings: Self = serde_json::from_slice(&body)
+            .map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        settings.sanitize();
+
+        Ok(settings)
+    }
+
+    // The listening addresses and the ban list aren't part of a rule set, so they're kept
+    pub fn merge_profile(&self, mut profile: Self) -> Self {
+        profile.server.address = self.server.address;
+        profile.server.additional_addresses = self.server.additional_addresses.clone();
+        profile.server.port = self.server.port;
+        profile.ban_list = self.ban_list.clone();
+
+        profile
+    }
+
+    // Reads the settings without creating or fixing the file
+    pub async fn read() -> Result<Self, String> {
+        let body = tokio::fs::read(Self::path_buf())
+            .await
+            .map_err(|e| format!("Failed to read settings.json: {}", e))?;
+
+        serde_json::from_slice(&body).map_err(|e| format!("Invalid settings.json: {}", e))
+    }
+
+    // Fails when settings.json has a value the server can't work with, see validate
+    pub async fn load() -> Result<Self, String> {
         let path = Self::path_buf();
         if !path.exists() {
-            return Self::load_default().await;
+            return Ok(Self::load_default().await);
         }
 
         let body = tokio::fs::read(path)
             .await
             .expect("Failed to read settings");
 
-        match serde_json::from_slice(&body) {
-            Ok(v) => {
+        match serde_json::from_slice::<Self>(&body) {
+            Ok(mut v) => {
+                v.sanitize();
+                v.check()?;
                 info!("Loaded settings.json");
-                v
+                Ok(v)
             }
             Err(_) => {
                 info!("Creating file settings.json. If you want to update it, stop the server, modify the file and restart the server");
-                Self::load_default().await
+                Ok(Self::load_default().await)
             }
         }
     }
@@ -169,24 +664,259 @@ impl Settings {
     }
 
     pub async fn save(&self) {
-        let path = Self::path_buf();
+        self.save_as(&Self::path_buf())
+            .await
+            .expect("Settings failed to save");
+    }
+
+    pub async fn save_as(&self, path: &Path) -> Result<(), String> {
         let serialized = serde_json::to_string_pretty(self).unwrap();
 
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+
         tokio::fs::write(path, serialized)
             .await
-            .expect("Settings failed to save");
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+        // The settings can contain secrets, so only the owner can read them
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let _ = tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                .await
+                .map_err(|err| {
+                    tracing::error!(%err, "Settings file permissions couldn't be restricted");
+                    err
+                });
+        }
+
+        Ok(())
     }
 
-    pub fn flip_in(&self, id: &Uuid) -> bool {
-        self.flip.enabled
-            && (self.flip.pov == FlipPov::Both || self.flip.pov == FlipPov::Others)
-            && self.flip.players.contains(id)
+    #[inline]
+    fn json_pointer(path: &str) -> String {
+        format!("/{}", path.replace('.', "/"))
     }
 
-    pub fn flip_not_in(&self, id: &Uuid) -> bool {
-        self.flip.enabled
-            && (self.flip.pov == FlipPov::Both || self.flip.pov == FlipPov::Self_)
-            && !self.flip.players.contains(id)
+    // Paths of the settings that are different in other, e.g. server.port
+    pub fn diff(&self, other: &Self) -> Vec<String> {
+        let (current, other) = match (serde_json::to_value(self), serde_json::to_value(other)) {
+            (Ok(Value::Object(current)), Ok(Value::Object(other))) => (current, other),
+            _ => return vec![],
+        };
+
+        let mut changed = vec![];
+
+        for (section, value) in &current {
+            match (value, other.get(section)) {
+                (Value::Object(fields), Some(Value::Object(other_fields))) => {
+                    for (field, value) in fields {
+                        if other_fields.get(field) != Some(value) {
+                            changed.push(format!("{}.{}", section, field));
+                        }
+                    }
+                }
+                (value, other_value) if other_value != Some(value) => changed.push(section.clone()),
+                _ => (),
+            }
+        }
+
+        changed
+    }
+
+    pub fn get_path(&self, path: &str) -> Result<Value, String> {
+        let settings = serde_json::to_value(self).map_err(|e| e.to_string())?;
+
+        settings
+            .pointer(&Self::json_pointer(path))
+            .cloned()
+            .ok_or_else(|| format!("Unknown setting {}", path))
+    }
+
+    pub fn set_path(&mut self, path: &str, raw: &str) -> Result<(), String> {
+        if path.is_empty()
+            || READ_ONLY_PATHS
+                .iter()
+                .any(|p| *p == path || p.starts_with(&format!("{}.", path)))
+        {
+            // restart only binds the server addresses again
+            return Err(format!(
+                "{} can't be updated while the server is running, update settings.json and {}",
+                path,
+                if path.starts_with("server") {
+                    "use restart"
+                } else {
+                    "restart the server"
+                }
+            ));
+        }
+
+        let mut settings = serde_json::to_value(&*self).map_err(|e| e.to_string())?;
+
+        let field = settings
+            .pointer_mut(&Self::json_pointer(path))
+            .ok_or_else(|| format!("Unknown setting {}", path))?;
+
+        // Values that aren't valid json are considered as strings so quotes are optional
+        *field = serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_owned()));
+
+        let mut updated: Self = serde_json::from_value(settings)
+            .map_err(|e| format!("Invalid value {} for {}: {}", raw, path, e))?;
+        updated.sanitize();
+        updated.check()?;
+
+        *self = updated;
+
+        Ok(())
+    }
+
+    // The errors of validate, the warnings don't prevent the settings from being used
+    pub fn check(&self) -> Result<(), String> {
+        let errors: Vec<String> = self
+            .validate()
+            .into_iter()
+            .filter_map(|issue| match issue {
+                ConfigIssue::Error(message) => Some(message),
+                ConfigIssue::Warning(_) => None,
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = vec![];
+
+        if self.server.max_players <= 0 {
+            issues.push(ConfigIssue::Error(
+                "server.max_players should be greater than 0".to_owned(),
+            ));
+        }
+
+        if self.server.port == 0 || self.server.port > u32::from(u16::MAX) {
+            issues.push(ConfigIssue::Error(format!(
+                "server.port should be between 1 and {}",
+                u16::MAX
+            )));
+        }
+
+        if self.server.max_tasks_per_connection == 0 {
+            issues.push(ConfigIssue::Warning(
+                "server.max_tasks_per_connection is 0, 1 will be used".to_owned(),
+            ));
+        }
+
+        for blocked in &self.server.blocked_packet_types {
+            if !Content::TYPE_NAMES.contains(&blocked.to_lowercase().as_str()) {
+                issues.push(ConfigIssue::Warning(format!(
+                    "server.blocked_packet_types contains {} which isn't a packet type, expected one of {}",
+                    blocked,
+                    Content::TYPE_NAMES.join(", ")
+                )));
+            }
+        }
+
+        if self.flip.players.contains(&Uuid::nil()) {
+            issues.push(ConfigIssue::Warning(
+                "flip.players contains the nil id, it will be removed".to_owned(),
+            ));
+        }
+
+        if self.persist_shines.enabled {
+            if self.persist_shines.file_name.is_empty() {
+                issues.push(ConfigIssue::Error(
+                    "persist_shines.file_name is empty".to_owned(),
+                ));
+            }
+
+            if self
+                .persist_shines
+                .profile_file_name(&self.persist_shines.active_profile)
+                .is_none()
+            {
+                issues.push(ConfigIssue::Warning(format!(
+                    "persist_shines.active_profile {} isn't in persist_shines.profiles, the default profile will be used",
+                    self.persist_shines.active_profile
+                )));
+            }
+        }
+
+        if self.persist_players.enabled && self.persist_players.file_name.is_empty() {
+            issues.push(ConfigIssue::Error(
+                "persist_players.file_name is empty".to_owned(),
+            ));
+        }
+
+        if self.persist_costumes.enabled && self.persist_costumes.file_name.is_empty() {
+            issues.push(ConfigIssue::Error(
+                "persist_costumes.file_name is empty".to_owned(),
+            ));
+        }
+
+        if self.server.max_packet_size < Content::LARGEST_BODY_SIZE {
+            issues.push(ConfigIssue::Error(format!(
+                "server.max_packet_size should be at least {}, the size of the biggest packet",
+                Content::LARGEST_BODY_SIZE
+            )));
+        }
+
+        if !(0. ..1.).contains(&self.server.position_smoothing) {
+            issues.push(ConfigIssue::Error(
+                "server.position_smoothing should be between 0 and 1, 1 excluded".to_owned(),
+            ));
+        }
+
+        if (1..MIN_READ_TIMEOUT_SECS).contains(&self.server.read_timeout_secs) {
+            issues.push(ConfigIssue::Error(format!(
+                "server.read_timeout_secs should be 0 or at least {}, the players send nothing while loading",
+                MIN_READ_TIMEOUT_SECS
+            )));
+        }
+
+        if self.tag.reveal_radius < 0. || self.tag.proximity_radius < 0. {
+            issues.push(ConfigIssue::Warning(
+                "tag.reveal_radius and tag.proximity_radius should be positive".to_owned(),
+            ));
+        }
+
+        if self.webhook.enabled && self.webhook.url.is_empty() {
+            issues.push(ConfigIssue::Error("webhook.url is empty".to_owned()));
+        }
+
+        if self.admin.enabled && !self.admin.address.is_loopback() && self.admin.token.is_none() {
+            issues.push(ConfigIssue::Error(
+                "admin.token is required when admin.address isn't a loopback address".to_owned(),
+            ));
+        }
+
+        issues
+    }
+
+    // Removes the values that would conflict with the server conventions
+    pub fn sanitize(&mut self) {
+        // The nil id is used by the server packets
+        if self.flip.players.contains(&Uuid::nil()) {
+            warn!("Removed the nil id from flip.players");
+            self.flip.players.retain(|id| !id.is_nil());
+        }
+    }
+
+    pub fn is_packet_blocked(&self, content: &Content) -> bool {
+        let type_name = content.type_name();
+
+        self.server
+            .blocked_packet_types
+            .iter()
+            .any(|blocked| blocked.to_lowercase() == type_name)
     }
 
     pub fn is_special_costume(&self, costume: &String) -> bool {
@@ -197,3 +927,100 @@ impl Settings {
         self.special_costumes.allowed_players.contains(id)
     }
 }
+
+#[derive(Debug)]
+pub enum ConfigIssue {
+    // The server can't work with this value
+    Error(String),
+    Warning(String),
+}
+
+// Settings read once per connection or packet, so a loadsettings or set happening meanwhile can't
+// give an inconsistent view of them. Only copied values, it's taken for every packet
+#[derive(Clone, Copy)]
+pub struct SettingsSnapshot {
+    pub max_players: i16,
+    pub max_tasks_per_connection: usize,
+    pub stage_scoping: bool,
+    pub max_malformed_packets: u32,
+    pub evict_when_full: bool,
+    pub hide_seekers: bool,
+    pub reveal_radius: f32,
+    pub proximity_radius: f32,
+    pub per_player_new_save: bool,
+    pub max_packets_per_second: u32,
+    pub read_timeout: Option<Duration>,
+    pub max_connections_per_ip: usize,
+    pub max_packet_size: usize,
+    pub record: bool,
+    pub position_smoothing: f32,
+}
+
+impl From<&Settings> for SettingsSnapshot {
+    fn from(settings: &Settings) -> Self {
+        Self {
+            max_players: settings.server.max_players,
+            max_tasks_per_connection: settings.server.max_tasks_per_connection,
+            stage_scoping: settings.server.stage_scoping,
+            max_malformed_packets: settings.server.max_malformed_packets,
+            evict_when_full: settings.server.evict_when_full,
+            hide_seekers: settings.tag.hide_seekers,
+            reveal_radius: settings.tag.reveal_radius,
+            proximity_radius: settings.tag.proximity_radius,
+            per_player_new_save: settings.persist_shines.per_player_new_save,
+            max_packets_per_second: settings.server.max_packets_per_second,
+            read_timeout: Some(Duration::from_secs(settings.server.read_timeout_secs))
+                .filter(|timeout| !timeout.is_zero()),
+            max_connections_per_ip: settings.server.max_connections_per_ip,
+            max_packet_size: settings.server.max_packet_size,
+            record: settings.record.enabled,
+            position_smoothing: settings.server.position_smoothing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_path_refuses_errors() {
+        let mut settings = Settings::default();
+
+        for (path, value) in [
+            ("server.max_packet_size", "10"),
+            ("server.position_smoothing", "1"),
+            ("server.read_timeout_secs", "5"),
+            ("server.max_players", "0"),
+        ] {
+            assert!(
+                settings.set_path(path, value).is_err(),
+                "{} {}",
+                path,
+                value
+            );
+        }
+
+        assert_eq!(settings.diff(&Settings::default()), Vec::<String>::new());
+
+        settings.set_path("server.read_timeout_secs", "0").unwrap();
+        settings.set_path("server.read_timeout_secs", "10").unwrap();
+        settings
+            .set_path("server.position_smoothing", "0.5")
+            .unwrap();
+
+        assert_eq!(settings.server.read_timeout_secs, 10);
+    }
+
+    #[test]
+    fn check_ignores_warnings() {
+        let mut settings = Settings::default();
+        settings.server.max_tasks_per_connection = 0;
+
+        assert!(settings.check().is_ok());
+
+        settings.server.max_packet_size = 0;
+
+        assert!(settings.check().is_err());
+    }
+}
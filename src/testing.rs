@@ -0,0 +1,164 @@
+// Runs a server on a random port and talks to it like the game clients do
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Instant};
+use uuid::Uuid;
+
+use crate::packet::{ConnectionType, Content, Header, Packet, Protocol, HEADER_SIZE};
+use crate::server::Server;
+use crate::settings::Settings;
+
+// Long enough for a loaded machine, the tests waiting for nothing to come pay it each time
+const RECV_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub async fn start(settings: Settings) -> (Arc<Server>, SocketAddr) {
+    let server = Arc::new(Server::new(settings));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn({
+        let server = server.clone();
+
+        async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(server.clone().handle_connection(socket));
+            }
+        }
+    });
+
+    (server, addr)
+}
+
+// Polls until the condition holds, false if it never did
+pub async fn wait_for<F, Fut>(mut condition: F) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    while Instant::now() < deadline {
+        if condition().await {
+            return true;
+        }
+
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    false
+}
+
+#[derive(Debug)]
+pub struct Client {
+    pub id: Uuid,
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    // Connects and waits until the server knows the player
+    pub async fn connect(server: &Server, addr: SocketAddr, name: &str) -> Self {
+        Self::connect_as(server, addr, Uuid::new_v4(), name).await
+    }
+
+    pub async fn connect_as(server: &Server, addr: SocketAddr, id: Uuid, name: &str) -> Self {
+        let mut client = Self::open(addr, id, name, ConnectionType::First).await;
+
+        assert!(
+            wait_for(|| async { server.connected_peers().await.contains(&id) }).await,
+            "{} couldn't join",
+            name
+        );
+
+        // Drops what the server sends on join, e.g. the costumes of the others
+        while client.recv().await.is_some() {}
+
+        client
+    }
+
+    // Only sends the connect packet, for the players that aren't expected to join
+    pub async fn open(addr: SocketAddr, id: Uuid, name: &str, type_: ConnectionType) -> Self {
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut client = Self { id, reader, writer };
+
+        assert!(matches!(
+            client.recv().await.map(|packet| packet.content),
+            Some(Content::Init { .. })
+        ));
+
+        client
+            .send(Content::Connect {
+                type_,
+                max_player: 8,
+                client: name.to_owned(),
+            })
+            .await;
+
+        client
+    }
+
+    pub async fn send(&mut self, content: Content) {
+        let packet = Packet::new(self.id, content);
+
+        // The server may have closed the connection already, recv tells it
+        let _ = self
+            .writer
+            .write_all(&packet.as_bytes(Protocol::Current))
+            .await;
+    }
+
+    // None when nothing came in time or the connection is closed
+    pub async fn recv(&mut self) -> Option<Packet> {
+        timeout(RECV_TIMEOUT, self.read_packet())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    // Skips the packets until one matches
+    pub async fn recv_until(&mut self, matches: impl Fn(&Packet) -> bool) -> Option<Packet> {
+        while let Some(packet) = self.recv().await {
+            if matches(&packet) {
+                return Some(packet);
+            }
+        }
+
+        None
+    }
+
+    // Skips the packets until the server closes the connection
+    pub async fn is_closed(&mut self) -> bool {
+        let closed = timeout(Duration::from_secs(2), async {
+            let mut buf = [0; 256];
+
+            loop {
+                match self.reader.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => (),
+                }
+            }
+        })
+        .await;
+
+        closed.is_ok()
+    }
+
+    async fn read_packet(&mut self) -> Option<Packet> {
+        let mut header = [0; HEADER_SIZE];
+        self.reader.read_exact(&mut header).await.ok()?;
+
+        let header = Header::from_bytes(Bytes::from(header.to_vec())).ok()?;
+
+        let mut body = vec![0; header.packet_size];
+        self.reader.read_exact(&mut body).await.ok()?;
+
+        header.make_packet(Bytes::from(body)).ok()
+    }
+}